@@ -0,0 +1,88 @@
+//! Feeds arbitrary server bytes to the WebSocket frame decoder and to a connected
+//! [`WebSocket`] with `permessage-deflate` negotiated, checking that neither panics
+//! nor buffers or inflates past its limits.
+
+#![no_main]
+
+use futures_lite::future::block_on;
+use libfuzzer_sys::fuzz_target;
+use sign_proto::deflate::DeflateConfig;
+use sign_proto::io::Connection;
+use sign_proto::ws::{
+    accept_key, base64_encode, decode_frame, HandshakeOptions, Limits, WebSocket, WsMessage,
+};
+use url::Url;
+
+const LIMITS: Limits = Limits {
+    max_frame_size: 256,
+    max_message_size: 1024,
+};
+
+/// Serves `data` in chunks of `chunk` bytes and discards everything written.
+struct Replay<'a> {
+    data: &'a [u8],
+    chunk: usize,
+}
+
+impl Connection for Replay<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let n = self.data.len().min(buf.len()).min(self.chunk);
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Ok(n)
+    }
+
+    async fn write_all(&mut self, _buf: &[u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+fn zero_random(buf: &mut [u8]) {
+    buf.fill(0);
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&chunk, frames)) = data.split_first() else {
+        return;
+    };
+
+    let mut buf = frames;
+    while let Ok(Some((frame, used))) = decode_frame(buf, &LIMITS) {
+        assert!(used <= buf.len());
+        assert!(frame.payload.len() <= LIMITS.max_frame_size);
+        buf = &buf[used..];
+    }
+
+    // The same bytes again, as they would arrive after a successful handshake
+    let key = base64_encode(&[0; 16]);
+    let mut stream = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\
+         Sec-WebSocket-Extensions: permessage-deflate\r\n\r\n",
+        accept_key(&key)
+    )
+    .into_bytes();
+    stream.extend_from_slice(frames);
+
+    let conn = Replay {
+        data: &stream,
+        chunk: chunk as usize + 1,
+    };
+    let url = Url::parse("ws://sign.invalid/ws").unwrap();
+    let options = HandshakeOptions {
+        deflate: Some(DeflateConfig::default()),
+        ..Default::default()
+    };
+    let mut ws = block_on(WebSocket::handshake(conn, &url, &options, zero_random)).unwrap();
+    ws.set_limits(LIMITS);
+
+    while let Ok(msg) = block_on(ws.recv()) {
+        match msg {
+            WsMessage::Binary(payload) => assert!(payload.len() <= LIMITS.max_message_size),
+            WsMessage::Text(text) => assert!(text.len() <= LIMITS.max_message_size),
+            _ => {}
+        }
+    }
+});
@@ -0,0 +1,167 @@
+//! The receiving half of the `permessage-deflate` WebSocket extension (RFC 7692).
+//!
+//! Only the server compresses. Deflating on the device would need a compressor with
+//! hash tables several times the size of the window, so we never set RSV1 on
+//! outgoing frames, which the extension allows.
+
+use miniz_oxide::inflate::stream::{inflate, InflateState};
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+
+use crate::ws::{HandshakeError, ProtocolError};
+
+pub const EXTENSION_NAME: &str = "permessage-deflate";
+
+/// Appended to every compressed message before inflating it. The sender strips it
+/// from the end of its sync flush (RFC 7692 section 7.2.1).
+const TAIL: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+/// Output is produced this many bytes at a time, so an oversized message is
+/// rejected before much of it has been inflated.
+const OUTPUT_CHUNK: usize = 1024;
+
+/// What to ask the server for in the `permessage-deflate` offer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DeflateConfig {
+    /// Upper bound on the server's LZ77 window, as a power of two from 8 to 15.
+    /// `None` leaves it up to the server. The inflater always keeps a full 32 KiB
+    /// window, so this only matters to a server short on memory.
+    pub server_max_window_bits: Option<u8>,
+    /// Ask the server to compress every message on its own, so that nothing from
+    /// one message is needed to inflate the next.
+    pub server_no_context_takeover: bool,
+}
+
+impl DeflateConfig {
+    /// Value for the `Sec-WebSocket-Extensions` request header.
+    pub fn offer(&self) -> String {
+        let mut offer = EXTENSION_NAME.to_string();
+        if let Some(bits) = self.server_max_window_bits {
+            offer.push_str(&format!("; server_max_window_bits={}", bits.clamp(8, 15)));
+        }
+        if self.server_no_context_takeover {
+            offer.push_str("; server_no_context_takeover");
+        }
+        offer
+    }
+}
+
+/// Parameters the server agreed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateParams {
+    pub server_max_window_bits: u8,
+    pub server_no_context_takeover: bool,
+}
+
+/// Checks the server's `Sec-WebSocket-Extensions` answer against what `config`
+/// offered, as RFC 7692 section 7.1 requires.
+pub fn negotiate(header: &str, config: &DeflateConfig) -> Result<DeflateParams, HandshakeError> {
+    let bad = || HandshakeError::BadExtension(header.to_string());
+
+    // We offered a single extension, so there can only be one in the answer
+    if header.contains(',') {
+        return Err(bad());
+    }
+    let mut parts = header.split(';').map(str::trim);
+    if parts.next() != Some(EXTENSION_NAME) {
+        return Err(bad());
+    }
+
+    let mut params = DeflateParams {
+        server_max_window_bits: 15,
+        server_no_context_takeover: false,
+    };
+    let mut seen = Vec::new();
+    for part in parts {
+        let (name, value) = match part.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (part, None),
+        };
+        if seen.contains(&name) {
+            return Err(bad());
+        }
+        seen.push(name);
+
+        match (name, value) {
+            ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+            // We never compress, so there's no context for us to keep
+            ("client_no_context_takeover", None) => {}
+            ("server_max_window_bits", Some(value)) => {
+                let bits: u8 = value.parse().map_err(|_| bad())?;
+                let max = config.server_max_window_bits.unwrap_or(15);
+                if !(8..=max).contains(&bits) {
+                    return Err(bad());
+                }
+                params.server_max_window_bits = bits;
+            }
+            // Includes client_max_window_bits, which we didn't offer
+            _ => return Err(bad()),
+        }
+    }
+
+    Ok(params)
+}
+
+/// Inflates compressed messages for one connection. Holds about 43 KiB of heap for
+/// the window and decoder tables, whatever the negotiated window size.
+pub struct Inflater {
+    params: DeflateParams,
+    state: Box<InflateState>,
+}
+
+impl Inflater {
+    pub fn new(params: DeflateParams) -> Self {
+        Self {
+            params,
+            state: InflateState::new_boxed(DataFormat::Raw),
+        }
+    }
+
+    /// Inflates one whole message. Fails with [`ProtocolError::MessageTooLarge`] as
+    /// soon as the output would exceed `max_len`.
+    pub fn inflate(&mut self, payload: &[u8], max_len: usize) -> Result<Vec<u8>, ProtocolError> {
+        let mut input = Vec::with_capacity(payload.len() + TAIL.len());
+        input.extend_from_slice(payload);
+        input.extend_from_slice(&TAIL);
+
+        let mut output = Vec::new();
+        let mut chunk = [0u8; OUTPUT_CHUNK];
+        let mut consumed = 0;
+        loop {
+            let result = inflate(
+                &mut self.state,
+                &input[consumed..],
+                &mut chunk,
+                MZFlush::Sync,
+            );
+            consumed += result.bytes_consumed;
+            let status = match result.status {
+                Ok(status) => status,
+                // No progress possible because everything has been inflated already
+                Err(MZError::Buf) if consumed == input.len() => break,
+                Err(_) => {
+                    self.state.reset(DataFormat::Raw);
+                    return Err(ProtocolError::InvalidCompression);
+                }
+            };
+
+            if output.len() + result.bytes_written > max_len {
+                self.state.reset(DataFormat::Raw);
+                return Err(ProtocolError::MessageTooLarge { max: max_len });
+            }
+            output.extend_from_slice(&chunk[..result.bytes_written]);
+
+            if status == MZStatus::StreamEnd {
+                // A final block ends the stream, so the next message starts a new one
+                self.state.reset(DataFormat::Raw);
+                break;
+            }
+            if consumed == input.len() && result.bytes_written < chunk.len() {
+                break;
+            }
+        }
+
+        if self.params.server_no_context_takeover {
+            self.state.reset(DataFormat::Raw);
+        }
+        Ok(output)
+    }
+}
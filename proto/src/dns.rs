@@ -0,0 +1,177 @@
+//! Just enough of RFC 1035 to look up A and AAAA records.
+
+use core::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const HEADER_LEN: usize = 12;
+const CLASS_IN: u16 = 1;
+/// Compressed names may point backwards at most this many times.
+const MAX_POINTERS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    A = 1,
+    Aaaa = 28,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub addr: IpAddr,
+    /// Time to live in seconds.
+    pub ttl: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsError {
+    /// The name is not a valid hostname.
+    InvalidName,
+    /// The response is truncated or otherwise unparseable.
+    Malformed,
+    /// The response answers a different query.
+    IdMismatch,
+    /// The name does not exist (`NXDOMAIN`).
+    NxDomain,
+    /// The server reported an error with this response code.
+    ServerFailure(u8),
+    /// The server answered, but without any usable address.
+    NoRecords,
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidName => write!(f, "invalid hostname"),
+            Self::Malformed => write!(f, "malformed DNS response"),
+            Self::IdMismatch => write!(f, "DNS response ID mismatch"),
+            Self::NxDomain => write!(f, "no such domain"),
+            Self::ServerFailure(rcode) => write!(f, "DNS server error (rcode {rcode})"),
+            Self::NoRecords => write!(f, "no address records"),
+        }
+    }
+}
+
+impl std::error::Error for DnsError {}
+
+/// Builds a recursive query for `name`.
+pub fn build_query(id: u16, name: &str, qtype: RecordType) -> Result<Vec<u8>, DnsError> {
+    let mut query = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+
+    query.extend_from_slice(&id.to_be_bytes());
+    // Standard query, recursion desired
+    query.extend_from_slice(&[0x01, 0x00]);
+    // One question, no answers, authority or additional records
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsError::InvalidName);
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+
+    query.extend_from_slice(&(qtype as u16).to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(query)
+}
+
+/// Returns the address records answering query `id`. CNAMEs and other record types
+/// are skipped, since recursive servers include the final addresses anyway.
+pub fn parse_response(id: u16, msg: &[u8]) -> Result<Vec<Record>, DnsError> {
+    if msg.len() < HEADER_LEN {
+        return Err(DnsError::Malformed);
+    }
+    if u16::from_be_bytes([msg[0], msg[1]]) != id {
+        return Err(DnsError::IdMismatch);
+    }
+    if msg[2] & 0x80 == 0 {
+        // Not a response
+        return Err(DnsError::Malformed);
+    }
+    match msg[3] & 0x0F {
+        0 => {}
+        3 => return Err(DnsError::NxDomain),
+        rcode => return Err(DnsError::ServerFailure(rcode)),
+    }
+
+    let questions = u16::from_be_bytes([msg[4], msg[5]]);
+    let answers = u16::from_be_bytes([msg[6], msg[7]]);
+
+    let mut pos = HEADER_LEN;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos)? + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(msg, pos)?;
+        let fixed = msg.get(pos..pos + 10).ok_or(DnsError::Malformed)?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let class = u16::from_be_bytes([fixed[2], fixed[3]]);
+        let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+        let len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        pos += 10;
+
+        let data = msg.get(pos..pos + len).ok_or(DnsError::Malformed)?;
+        pos += len;
+
+        if class != CLASS_IN {
+            continue;
+        }
+        let addr = match (rtype, data.len()) {
+            (1, 4) => IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+            (28, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(data);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => continue,
+        };
+        records.push(Record { addr, ttl });
+    }
+
+    Ok(records)
+}
+
+/// Returns the offset just past the (possibly compressed) name starting at `pos`.
+fn skip_name(msg: &[u8], mut pos: usize) -> Result<usize, DnsError> {
+    let mut pointers = 0;
+    let mut end = None;
+
+    loop {
+        let len = *msg.get(pos).ok_or(DnsError::Malformed)?;
+        match len & 0xC0 {
+            0x00 if len == 0 => return Ok(end.unwrap_or(pos + 1)),
+            0x00 => pos += 1 + len as usize,
+            0xC0 => {
+                let low = *msg.get(pos + 1).ok_or(DnsError::Malformed)?;
+                end.get_or_insert(pos + 2);
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err(DnsError::Malformed);
+                }
+                pos = (((len & 0x3F) as usize) << 8) | low as usize;
+            }
+            _ => return Err(DnsError::Malformed),
+        }
+    }
+}
+
+/// Sorts addresses for connection attempts as in RFC 8305 section 4: IPv6 first,
+/// then alternating between families so one broken family can't stall every attempt.
+pub fn happy_eyeballs_order(addrs: &[IpAddr]) -> Vec<IpAddr> {
+    let mut v6 = addrs.iter().filter(|a| a.is_ipv6()).copied();
+    let mut v4 = addrs.iter().filter(|a| a.is_ipv4()).copied();
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    loop {
+        let (a, b) = (v6.next(), v4.next());
+        if a.is_none() && b.is_none() {
+            return ordered;
+        }
+        ordered.extend(a);
+        ordered.extend(b);
+    }
+}
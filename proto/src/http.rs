@@ -0,0 +1,188 @@
+use core::fmt;
+
+use url::Url;
+
+use crate::io::{read_head_bytes, Connection};
+
+const MAX_HEAD_LEN: usize = 16384;
+/// Largest body [`read_body`] is usually given room for. Bodies are buffered whole,
+/// so this bounds how much memory a single response can take.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// The server sent, or announced, a body larger than the caller's limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BodyTooLarge {
+    pub max: usize,
+}
+
+impl fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Response body exceeds the {} byte limit", self.max)
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name_lower = name.to_lowercase();
+        self.headers
+            .iter()
+            .find(|(k, _)| k.to_lowercase() == name_lower)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn etag(&self) -> Option<&str> {
+        self.header("etag")
+    }
+
+    /// `Retry-After` in seconds. The HTTP-date form is not supported.
+    pub fn retry_after_secs(&self) -> Option<u64> {
+        self.header("retry-after")?.trim().parse().ok()
+    }
+
+    /// Unix time at which an exhausted rate limit resets, from `X-RateLimit-Reset`.
+    /// `None` unless `X-RateLimit-Remaining` says the limit is actually used up.
+    pub fn rate_limit_reset(&self) -> Option<i64> {
+        let remaining: u64 = self.header("x-ratelimit-remaining")?.trim().parse().ok()?;
+        if remaining > 0 {
+            return None;
+        }
+        self.header("x-ratelimit-reset")?.trim().parse().ok()
+    }
+}
+
+/// Path and query of `url`, as it appears in a request line.
+pub fn request_target(url: &Url) -> String {
+    if let Some(q) = url.query() {
+        format!("{}?{}", url.path(), q)
+    } else {
+        url.path().to_string()
+    }
+}
+
+pub fn build_request_head(method: &str, url: &Url, headers: &[(&str, &str)]) -> String {
+    let path = request_target(url);
+    let host = url.host_str().unwrap_or("");
+
+    let mut req =
+        format!("{method} {path} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: PHSign/1.0.0\r\n");
+    for (k, v) in headers {
+        req.push_str(&format!("{k}: {v}\r\n"));
+    }
+    req.push_str("Connection: close\r\n\r\n");
+    req
+}
+
+/// Writes a complete request. `Content-Length` is added for anything but `GET`.
+pub async fn write_request<C: Connection>(
+    conn: &mut C,
+    method: &str,
+    url: &Url,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> anyhow::Result<()> {
+    let len_str = body.len().to_string();
+    let mut headers = headers.to_vec();
+    if method != "GET" {
+        headers.push(("Content-Length", &len_str));
+    }
+
+    let req = build_request_head(method, url, &headers);
+    conn.write_all(req.as_bytes()).await?;
+    if !body.is_empty() {
+        conn.write_all(body).await?;
+    }
+
+    Ok(())
+}
+
+/// Parses a status line and headers. The returned response has an empty body.
+pub fn parse_head(head: &[u8]) -> anyhow::Result<HttpResponse> {
+    let header_str = core::str::from_utf8(head)?;
+    let mut lines = header_str.split("\r\n");
+
+    // Parse status line
+    let status_line = lines
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Missing status line"))?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .ok_or_else(|| anyhow::anyhow!("Missing status code"))?
+        .parse::<u16>()?;
+
+    // Parse headers
+    let mut headers = Vec::new();
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+        if let Some((k, v)) = line.split_once(':') {
+            headers.push((k.to_string(), v.trim().to_string()));
+        }
+    }
+
+    Ok(HttpResponse {
+        status,
+        headers,
+        body: Vec::new(),
+    })
+}
+
+/// Reads the status line and headers, leaving `conn` positioned at the body.
+pub async fn read_head<C: Connection>(conn: &mut C) -> anyhow::Result<HttpResponse> {
+    let head = read_head_bytes(conn, MAX_HEAD_LEN).await?;
+    parse_head(&head)
+}
+
+/// Reads the body described by `head`, either `Content-Length` bytes or until EOF.
+/// Fails with [`BodyTooLarge`] rather than buffer more than `max_size` bytes.
+pub async fn read_body<C: Connection>(
+    conn: &mut C,
+    head: &HttpResponse,
+    max_size: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let content_length: Option<usize> = head.header("content-length").and_then(|v| v.parse().ok());
+    let too_large = || anyhow::Error::new(BodyTooLarge { max: max_size });
+
+    let body = if let Some(len) = content_length {
+        if len > max_size {
+            return Err(too_large());
+        }
+        let mut body = vec![0u8; len];
+        let mut read_total = 0;
+        while read_total < len {
+            let n = conn.read(&mut body[read_total..]).await?;
+            if n == 0 {
+                break;
+            }
+            read_total += n;
+        }
+        body.truncate(read_total);
+        body
+    } else {
+        // Read until EOF
+        let mut body = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = conn.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            if body.len() + n > max_size {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&buf[..n]);
+        }
+        body
+    };
+
+    Ok(body)
+}
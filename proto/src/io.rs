@@ -0,0 +1,72 @@
+use std::net::TcpStream;
+
+/// A bidirectional byte stream.
+///
+/// On the device this is a TLS stream; on the host it is usually a [`TcpStream`].
+#[allow(async_fn_in_trait)]
+pub trait Connection {
+    /// Reads some bytes into `buf`, returning 0 once the peer has closed the stream.
+    async fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize>;
+
+    async fn write_all(&mut self, buf: &[u8]) -> anyhow::Result<()>;
+}
+
+impl<C: Connection + ?Sized> Connection for &mut C {
+    async fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        (**self).read(buf).await
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        (**self).write_all(buf).await
+    }
+}
+
+/// Blocking implementation for host-side tests and tools. Never use this on an
+/// executor that has other work to do.
+impl Connection for TcpStream {
+    async fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        Ok(std::io::Read::read(self, buf)?)
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        Ok(std::io::Write::write_all(self, buf)?)
+    }
+}
+
+pub async fn read_exact<C: Connection>(conn: &mut C, buf: &mut [u8]) -> anyhow::Result<()> {
+    let mut offset = 0;
+    while offset < buf.len() {
+        let n = conn.read(&mut buf[offset..]).await?;
+        if n == 0 {
+            anyhow::bail!("Connection closed unexpectedly");
+        }
+        offset += n;
+    }
+    Ok(())
+}
+
+/// Reads an HTTP head byte by byte until the blank line, leaving the stream
+/// positioned at the start of the body.
+pub async fn read_head_bytes<C: Connection>(
+    conn: &mut C,
+    max_len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let mut header_buf = Vec::with_capacity(1024);
+    let mut b = [0u8; 1];
+
+    loop {
+        let n = conn.read(&mut b).await?;
+        if n == 0 {
+            anyhow::bail!("Connection closed before headers complete");
+        }
+        header_buf.push(b[0]);
+
+        if header_buf.len() >= 4 && header_buf[header_buf.len() - 4..] == *b"\r\n\r\n" {
+            return Ok(header_buf);
+        }
+
+        if header_buf.len() > max_len {
+            anyhow::bail!("Headers too large");
+        }
+    }
+}
@@ -0,0 +1,12 @@
+//! Protocol code shared by the sign firmware and its host-side tests.
+//!
+//! Everything in here is written against [`io::Connection`] rather than a concrete
+//! socket, so the same HTTP and WebSocket code runs over ESP-TLS on the device and
+//! over a plain [`std::net::TcpStream`] on a development machine.
+
+pub mod deflate;
+pub mod dns;
+pub mod http;
+pub mod io;
+pub mod sha1;
+pub mod ws;
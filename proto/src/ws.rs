@@ -0,0 +1,798 @@
+use core::fmt;
+
+use url::Url;
+
+use crate::deflate::{self, DeflateConfig, Inflater};
+use crate::http::{parse_head, request_target, HttpResponse};
+use crate::io::{read_head_bytes, Connection};
+use crate::sha1::sha1;
+
+const MAX_HANDSHAKE_LEN: usize = 8192;
+
+/// Bytes requested from the connection at a time while waiting for a frame.
+const READ_CHUNK: usize = 1024;
+/// Largest payload a control frame may carry.
+const MAX_CONTROL_PAYLOAD: u64 = 125;
+
+#[derive(Debug)]
+pub enum WsMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// `None` when the close frame carried no status code.
+    Close(Option<CloseFrame>),
+}
+
+/// Status codes from RFC 6455 section 7.4.1, plus the IANA-registered 1012 and 1013.
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const UNSUPPORTED_DATA: u16 = 1003;
+    pub const INVALID_PAYLOAD: u16 = 1007;
+    pub const POLICY_VIOLATION: u16 = 1008;
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+    pub const INTERNAL_ERROR: u16 = 1011;
+    pub const SERVICE_RESTART: u16 = 1012;
+    pub const TRY_AGAIN_LATER: u16 = 1013;
+
+    /// Whether `code` may appear in a close frame. 1005, 1006 and 1015 are reserved
+    /// for reporting locally and must never be sent.
+    pub fn is_valid(code: u16) -> bool {
+        matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+/// Status code and reason of a close frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl CloseFrame {
+    pub fn new(code: u16, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+
+    /// Parses a close frame payload, which is either empty or a status code followed
+    /// by a UTF-8 reason.
+    pub fn parse(payload: &[u8]) -> Result<Option<Self>, ProtocolError> {
+        let (code, reason) = match payload {
+            [] => return Ok(None),
+            [hi, lo, reason @ ..] => (u16::from_be_bytes([*hi, *lo]), reason),
+            _ => return Err(ProtocolError::InvalidClose),
+        };
+        if !close_code::is_valid(code) {
+            return Err(ProtocolError::InvalidClose);
+        }
+        let reason = core::str::from_utf8(reason).map_err(|_| ProtocolError::InvalidUtf8)?;
+
+        Ok(Some(Self {
+            code,
+            reason: reason.to_string(),
+        }))
+    }
+
+    /// Encodes the payload, truncating the reason on a character boundary so the
+    /// frame stays within the 125 byte control frame limit.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut end = self.reason.len().min(123);
+        while !self.reason.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        let mut payload = self.code.to_be_bytes().to_vec();
+        payload.extend_from_slice(&self.reason.as_bytes()[..end]);
+        payload
+    }
+}
+
+impl fmt::Display for CloseFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.reason.is_empty() {
+            write!(f, "{}", self.code)
+        } else {
+            write!(f, "{} ({})", self.code, self.reason)
+        }
+    }
+}
+
+/// Bounds on what the server may send. Frames are buffered whole before they are
+/// decoded, so these also bound how much memory a connection can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Largest payload accepted in a single frame.
+    pub max_frame_size: usize,
+    /// Largest reassembled text or binary message.
+    pub max_message_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 16 * 1024,
+            max_message_size: 64 * 1024,
+        }
+    }
+}
+
+/// A violation of RFC 6455 or of the configured [`Limits`] by the server. Receiving
+/// one fails the connection with [`ProtocolError::close_code`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    FrameTooLarge {
+        len: u64,
+        max: usize,
+    },
+    MessageTooLarge {
+        max: usize,
+    },
+    /// RSV1-3 are set without an extension that defines them.
+    ReservedBits,
+    /// Servers must not mask their frames.
+    MaskedFrame,
+    UnknownOpcode(u8),
+    /// A control frame is fragmented or longer than 125 bytes.
+    InvalidControlFrame,
+    /// A continuation frame arrived without a message to continue.
+    UnexpectedContinuation,
+    /// A new message started before the previous one's final fragment.
+    InterruptedMessage,
+    InvalidUtf8,
+    /// A close frame with a truncated payload or a reserved status code.
+    InvalidClose,
+    /// A compressed message that doesn't inflate.
+    InvalidCompression,
+}
+
+impl ProtocolError {
+    /// Status code to close the connection with.
+    pub fn close_code(&self) -> u16 {
+        match self {
+            Self::FrameTooLarge { .. } | Self::MessageTooLarge { .. } => {
+                close_code::MESSAGE_TOO_BIG
+            }
+            Self::InvalidUtf8 | Self::InvalidCompression => close_code::INVALID_PAYLOAD,
+            _ => close_code::PROTOCOL_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FrameTooLarge { len, max } => {
+                write!(f, "Frame of {len} bytes exceeds the {max} byte limit")
+            }
+            Self::MessageTooLarge { max } => write!(f, "Message exceeds the {max} byte limit"),
+            Self::ReservedBits => write!(f, "Reserved bits set"),
+            Self::MaskedFrame => write!(f, "Masked frame from server"),
+            Self::UnknownOpcode(op) => write!(f, "Unknown opcode {op:#x}"),
+            Self::InvalidControlFrame => write!(f, "Fragmented or oversized control frame"),
+            Self::UnexpectedContinuation => {
+                write!(f, "Continuation frame without a message to continue")
+            }
+            Self::InterruptedMessage => {
+                write!(f, "New message started before the previous one finished")
+            }
+            Self::InvalidUtf8 => write!(f, "Invalid UTF-8 in text message"),
+            Self::InvalidClose => write!(f, "Invalid close frame"),
+            Self::InvalidCompression => write!(f, "Invalid compressed message"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpCode {
+    Continuation = 0x0,
+    Text = 0x1,
+    Binary = 0x2,
+    Close = 0x8,
+    Ping = 0x9,
+    Pong = 0xA,
+}
+
+impl OpCode {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0x0 => Self::Continuation,
+            0x1 => Self::Text,
+            0x2 => Self::Binary,
+            0x8 => Self::Close,
+            0x9 => Self::Ping,
+            0xA => Self::Pong,
+            _ => return None,
+        })
+    }
+
+    /// Control frames may arrive between the fragments of a message.
+    pub fn is_control(self) -> bool {
+        self as u8 & 0x8 != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    /// RSV1, which marks a compressed message when `permessage-deflate` is in use.
+    pub rsv1: bool,
+    pub opcode: OpCode,
+    /// Unmasked payload.
+    pub payload: Vec<u8>,
+}
+
+/// Decodes the frame at the start of `buf`, returning it with the number of bytes it
+/// took up, or `None` if `buf` doesn't hold a whole frame yet. Oversized frames are
+/// rejected from their header alone, before any of the payload has to be buffered.
+pub fn decode_frame(buf: &[u8], limits: &Limits) -> Result<Option<(Frame, usize)>, ProtocolError> {
+    let Some(header) = buf.get(..2) else {
+        return Ok(None);
+    };
+
+    let fin = header[0] & 0x80 != 0;
+    let rsv1 = header[0] & 0x40 != 0;
+    // RSV1 is left for the caller to check, since only it knows about extensions
+    if header[0] & 0x30 != 0 {
+        return Err(ProtocolError::ReservedBits);
+    }
+    let opcode =
+        OpCode::from_u8(header[0] & 0x0F).ok_or(ProtocolError::UnknownOpcode(header[0] & 0x0F))?;
+    let masked = header[1] & 0x80 != 0;
+
+    let mut pos = 2;
+    let payload_len = match header[1] & 0x7F {
+        126 => {
+            let Some(len) = buf.get(pos..pos + 2) else {
+                return Ok(None);
+            };
+            pos += 2;
+            u16::from_be_bytes([len[0], len[1]]) as u64
+        }
+        127 => {
+            let Some(len) = buf.get(pos..pos + 8) else {
+                return Ok(None);
+            };
+            pos += 8;
+            u64::from_be_bytes(len.try_into().unwrap())
+        }
+        len => len as u64,
+    };
+
+    if opcode.is_control() && (!fin || payload_len > MAX_CONTROL_PAYLOAD) {
+        return Err(ProtocolError::InvalidControlFrame);
+    }
+    if payload_len > limits.max_frame_size as u64 {
+        return Err(ProtocolError::FrameTooLarge {
+            len: payload_len,
+            max: limits.max_frame_size,
+        });
+    }
+    // RFC 6455 section 5.1: a client must fail on any masked frame from the server
+    if masked {
+        return Err(ProtocolError::MaskedFrame);
+    }
+
+    let payload_len = payload_len as usize;
+    let Some(payload) = buf.get(pos..pos + payload_len) else {
+        return Ok(None);
+    };
+    let payload = payload.to_vec();
+
+    Ok(Some((
+        Frame {
+            fin,
+            rsv1,
+            opcode,
+            payload,
+        },
+        pos + payload_len,
+    )))
+}
+
+/// Extra parts of the opening handshake.
+#[derive(Debug, Clone, Default)]
+pub struct HandshakeOptions {
+    /// Subprotocols offered in `Sec-WebSocket-Protocol`, most preferred first.
+    pub protocols: Vec<String>,
+    /// Additional request headers, e.g. `Authorization`.
+    pub headers: Vec<(String, String)>,
+    /// Offer `permessage-deflate` with these parameters.
+    pub deflate: Option<DeflateConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// The server answered with something other than `101 Switching Protocols`.
+    Status(u16),
+    /// `Upgrade` or `Connection` don't name the WebSocket upgrade.
+    NotUpgraded,
+    /// `Sec-WebSocket-Accept` is missing or doesn't match the key we sent.
+    BadAccept,
+    /// The server selected a subprotocol we didn't offer.
+    UnexpectedProtocol(String),
+    /// `Sec-WebSocket-Extensions` names an extension or parameter we didn't offer.
+    BadExtension(String),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Status(status) => write!(f, "WebSocket handshake failed with status {status}"),
+            Self::NotUpgraded => write!(f, "Server did not upgrade the connection"),
+            Self::BadAccept => write!(f, "Invalid Sec-WebSocket-Accept"),
+            Self::UnexpectedProtocol(p) => write!(f, "Server selected unoffered subprotocol {p}"),
+            Self::BadExtension(e) => write!(f, "Server accepted invalid extension {e}"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// The `Sec-WebSocket-Accept` value a server must answer `key` with.
+pub fn accept_key(key: &str) -> String {
+    const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+    base64_encode(&sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+/// Checks the server's handshake response and returns the selected subprotocol.
+pub fn validate_handshake(
+    resp: &HttpResponse,
+    key: &str,
+    offered_protocols: &[String],
+) -> Result<Option<String>, HandshakeError> {
+    if resp.status != 101 {
+        return Err(HandshakeError::Status(resp.status));
+    }
+
+    let upgrade = resp.header("upgrade").unwrap_or("");
+    let connection = resp.header("connection").unwrap_or("");
+    if !upgrade.eq_ignore_ascii_case("websocket")
+        || !connection
+            .split(',')
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+    {
+        return Err(HandshakeError::NotUpgraded);
+    }
+
+    if resp.header("sec-websocket-accept") != Some(accept_key(key).as_str()) {
+        return Err(HandshakeError::BadAccept);
+    }
+
+    match resp.header("sec-websocket-protocol") {
+        Some(p) if offered_protocols.iter().any(|offered| offered == p) => Ok(Some(p.to_string())),
+        Some(p) => Err(HandshakeError::UnexpectedProtocol(p.to_string())),
+        None => Ok(None),
+    }
+}
+
+/// A fragmented message received so far.
+struct Partial {
+    opcode: OpCode,
+    compressed: bool,
+    payload: Vec<u8>,
+}
+
+/// Client side of a WebSocket connection.
+///
+/// Received bytes are buffered until a whole frame is available, and partially
+/// received messages are kept across calls, so [`WebSocket::recv`] can be cancelled
+/// (e.g. by a timeout) without losing data as long as the underlying connection's
+/// `read` is cancel-safe.
+pub struct WebSocket<C> {
+    conn: C,
+    /// Source of the handshake key and frame masks.
+    fill_random: fn(&mut [u8]),
+    read_buf: Vec<u8>,
+    /// A fragmented message still waiting for its final frame.
+    partial: Option<Partial>,
+    /// Outgoing data messages larger than this are split into continuation frames.
+    max_fragment_size: Option<usize>,
+    limits: Limits,
+    protocol: Option<String>,
+    /// Present when `permessage-deflate` was negotiated.
+    inflater: Option<Inflater>,
+    /// We have sent a close frame, so no more data may be sent.
+    close_sent: bool,
+    /// The server has sent a close frame, so nothing more will arrive.
+    close_received: Option<Option<CloseFrame>>,
+    /// Encoded control frames [`WebSocket::recv`] answered with, waiting for
+    /// [`WebSocket::flush`].
+    queued: Vec<u8>,
+}
+
+impl<C: Connection> WebSocket<C> {
+    /// Runs the opening handshake for `url` over an already-connected stream and
+    /// validates the server's answer as RFC 6455 section 4.1 requires.
+    pub async fn handshake(
+        mut conn: C,
+        url: &Url,
+        options: &HandshakeOptions,
+        fill_random: fn(&mut [u8]),
+    ) -> anyhow::Result<Self> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("No host in URL"))?;
+        let path = request_target(url);
+
+        let mut key_bytes = [0u8; 16];
+        fill_random(&mut key_bytes);
+        let ws_key = base64_encode(&key_bytes);
+
+        // Send WebSocket upgrade request
+        let mut req = format!(
+            "GET {path} HTTP/1.1\r\n\
+             Host: {host}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {ws_key}\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             User-Agent: PHSign/1.0.0\r\n"
+        );
+        if !options.protocols.is_empty() {
+            req.push_str(&format!(
+                "Sec-WebSocket-Protocol: {}\r\n",
+                options.protocols.join(", ")
+            ));
+        }
+        if let Some(deflate) = &options.deflate {
+            req.push_str(&format!(
+                "Sec-WebSocket-Extensions: {}\r\n",
+                deflate.offer()
+            ));
+        }
+        for (k, v) in &options.headers {
+            req.push_str(&format!("{k}: {v}\r\n"));
+        }
+        req.push_str("\r\n");
+
+        conn.write_all(req.as_bytes()).await?;
+
+        let head = read_head_bytes(&mut conn, MAX_HANDSHAKE_LEN).await?;
+        let resp = parse_head(&head)?;
+        let protocol = validate_handshake(&resp, &ws_key, &options.protocols)?;
+        let inflater = match (resp.header("sec-websocket-extensions"), &options.deflate) {
+            (None, _) => None,
+            (Some(accepted), Some(offered)) => {
+                Some(Inflater::new(deflate::negotiate(accepted, offered)?))
+            }
+            (Some(accepted), None) => {
+                return Err(HandshakeError::BadExtension(accepted.to_string()).into())
+            }
+        };
+
+        Ok(Self {
+            conn,
+            fill_random,
+            read_buf: Vec::new(),
+            partial: None,
+            max_fragment_size: None,
+            limits: Limits::default(),
+            protocol,
+            inflater,
+            close_sent: false,
+            close_received: None,
+            queued: Vec::new(),
+        })
+    }
+
+    /// Subprotocol selected by the server, if any.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Whether the server may send compressed messages.
+    pub fn is_compressed(&self) -> bool {
+        self.inflater.is_some()
+    }
+
+    pub fn connection_mut(&mut self) -> &mut C {
+        &mut self.conn
+    }
+
+    /// Splits outgoing text and binary messages into fragments of at most `size`
+    /// payload bytes. `None` (the default) sends every message as a single frame.
+    pub fn set_max_fragment_size(&mut self, size: Option<usize>) {
+        self.max_fragment_size = size.map(|size| size.max(1));
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Sends `msg`, after any replies [`WebSocket::recv`] queued. Sending a
+    /// [`WsMessage::Close`] starts the closing handshake, after which nothing else can
+    /// be sent; prefer [`WebSocket::close`], which also waits for the server's answer.
+    pub async fn send(&mut self, msg: &WsMessage) -> anyhow::Result<()> {
+        self.flush().await?;
+        if self.close_sent {
+            anyhow::bail!("WebSocket is closing");
+        }
+
+        let close_payload;
+        let (opcode, payload) = match msg {
+            WsMessage::Text(s) => (OpCode::Text, s.as_bytes()),
+            WsMessage::Binary(b) => (OpCode::Binary, b.as_slice()),
+            WsMessage::Ping(b) => (OpCode::Ping, b.as_slice()),
+            WsMessage::Pong(b) => (OpCode::Pong, b.as_slice()),
+            WsMessage::Close(frame) => {
+                close_payload = frame.as_ref().map(CloseFrame::encode).unwrap_or_default();
+                self.close_sent = true;
+                (OpCode::Close, close_payload.as_slice())
+            }
+        };
+
+        match self.max_fragment_size {
+            Some(size) if !opcode.is_control() && payload.len() > size => {
+                let mut chunks = payload.chunks(size).peekable();
+                let mut frame_opcode = opcode;
+                while let Some(chunk) = chunks.next() {
+                    let fin = chunks.peek().is_none();
+                    self.send_frame(fin, frame_opcode, chunk).await?;
+                    frame_opcode = OpCode::Continuation;
+                }
+                Ok(())
+            }
+            _ => self.send_frame(true, opcode, payload).await,
+        }
+    }
+
+    /// Writes the pongs and close frames [`WebSocket::recv`] queued. Like `send`, this
+    /// must not be cancelled partway, or a partial frame is left on the stream.
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        if self.queued.is_empty() {
+            return Ok(());
+        }
+        let queued = core::mem::take(&mut self.queued);
+        self.conn.write_all(&queued).await
+    }
+
+    /// Queues a frame for [`WebSocket::flush`], so `recv` never writes.
+    fn queue_frame(&mut self, opcode: OpCode, payload: &[u8]) {
+        let mut mask = [0u8; 4];
+        (self.fill_random)(&mut mask);
+        self.queued
+            .extend_from_slice(&encode_frame(true, opcode, payload, mask));
+    }
+
+    /// Queues a close frame, starting the closing handshake.
+    fn queue_close(&mut self, frame: Option<CloseFrame>) {
+        let payload = frame.as_ref().map(CloseFrame::encode).unwrap_or_default();
+        self.queue_frame(OpCode::Close, &payload);
+        self.close_sent = true;
+    }
+
+    async fn send_frame(
+        &mut self,
+        fin: bool,
+        opcode: OpCode,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        let mut mask = [0u8; 4];
+        (self.fill_random)(&mut mask);
+
+        let frame = encode_frame(fin, opcode, payload, mask);
+        self.conn.write_all(&frame).await
+    }
+
+    /// Returns the next complete message, reassembling fragmented ones. Pings are
+    /// answered automatically and are not returned. A close from the server is
+    /// echoed as RFC 6455 section 5.5.1 requires, and returned as
+    /// [`WsMessage::Close`]; reading after that is an error.
+    ///
+    /// If the server violates the protocol, a close frame with the matching status
+    /// code is queued and the [`ProtocolError`] is returned. The connection should be
+    /// dropped after flushing it.
+    ///
+    /// Never writes: the answers are queued until the next [`WebSocket::flush`],
+    /// `send` or `close`, so `recv` can be raced against other work without leaving a
+    /// partial frame on the stream. Callers should flush after each message.
+    pub async fn recv(&mut self) -> anyhow::Result<WsMessage> {
+        if self.close_received.is_some() {
+            anyhow::bail!("WebSocket is closed");
+        }
+
+        let result = self.recv_message().await;
+        if let Err(e) = &result {
+            if let Some(error) = e.downcast_ref::<ProtocolError>() {
+                if !self.close_sent {
+                    let close = CloseFrame::new(error.close_code(), error.to_string());
+                    self.queue_close(Some(close));
+                }
+            }
+        }
+        result
+    }
+
+    async fn recv_message(&mut self) -> anyhow::Result<WsMessage> {
+        loop {
+            let frame = self.read_frame().await?;
+            // Only the first frame of a data message may be marked as compressed
+            let rsv1_allowed =
+                self.inflater.is_some() && matches!(frame.opcode, OpCode::Text | OpCode::Binary);
+            if frame.rsv1 && !rsv1_allowed {
+                return Err(ProtocolError::ReservedBits.into());
+            }
+
+            let (opcode, compressed, payload) = match frame.opcode {
+                OpCode::Ping => {
+                    if !self.close_sent {
+                        self.queue_frame(OpCode::Pong, &frame.payload);
+                    }
+                    continue;
+                }
+                OpCode::Pong => return Ok(WsMessage::Pong(frame.payload)),
+                OpCode::Close => {
+                    let close = CloseFrame::parse(&frame.payload)?;
+                    self.close_received = Some(close.clone());
+                    if !self.close_sent {
+                        // Echo the code; an empty close is answered with an empty one
+                        let echo = close.as_ref().map(|c| CloseFrame::new(c.code, ""));
+                        self.queue_close(echo);
+                    }
+                    return Ok(WsMessage::Close(close));
+                }
+                OpCode::Text | OpCode::Binary => {
+                    if self.partial.is_some() {
+                        return Err(ProtocolError::InterruptedMessage.into());
+                    }
+                    if frame.payload.len() > self.limits.max_message_size {
+                        return Err(ProtocolError::MessageTooLarge {
+                            max: self.limits.max_message_size,
+                        }
+                        .into());
+                    }
+                    if !frame.fin {
+                        self.partial = Some(Partial {
+                            opcode: frame.opcode,
+                            compressed: frame.rsv1,
+                            payload: frame.payload,
+                        });
+                        continue;
+                    }
+                    (frame.opcode, frame.rsv1, frame.payload)
+                }
+                OpCode::Continuation => {
+                    let Some(mut partial) = self.partial.take() else {
+                        return Err(ProtocolError::UnexpectedContinuation.into());
+                    };
+                    if partial.payload.len() + frame.payload.len() > self.limits.max_message_size {
+                        return Err(ProtocolError::MessageTooLarge {
+                            max: self.limits.max_message_size,
+                        }
+                        .into());
+                    }
+                    partial.payload.extend_from_slice(&frame.payload);
+                    if !frame.fin {
+                        self.partial = Some(partial);
+                        continue;
+                    }
+                    (partial.opcode, partial.compressed, partial.payload)
+                }
+            };
+
+            let payload = match (compressed, &mut self.inflater) {
+                (true, Some(inflater)) => {
+                    inflater.inflate(&payload, self.limits.max_message_size)?
+                }
+                _ => payload,
+            };
+
+            return match opcode {
+                OpCode::Text => Ok(WsMessage::Text(
+                    String::from_utf8(payload).map_err(|_| ProtocolError::InvalidUtf8)?,
+                )),
+                _ => Ok(WsMessage::Binary(payload)),
+            };
+        }
+    }
+
+    async fn read_frame(&mut self) -> anyhow::Result<Frame> {
+        loop {
+            if let Some((frame, len)) = decode_frame(&self.read_buf, &self.limits)? {
+                self.read_buf.drain(..len);
+                return Ok(frame);
+            }
+
+            let mut chunk = [0u8; READ_CHUNK];
+            let n = self.conn.read(&mut chunk).await?;
+            if n == 0 {
+                anyhow::bail!("Connection closed unexpectedly");
+            }
+            self.read_buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Runs the closing handshake: sends `frame` unless a close was already sent,
+    /// then discards incoming messages until the server's close arrives, and returns
+    /// it. This waits for as long as the server takes, so callers should put a
+    /// timeout on it.
+    pub async fn close(&mut self, frame: Option<CloseFrame>) -> anyhow::Result<Option<CloseFrame>> {
+        if self.close_sent {
+            self.flush().await?;
+        } else {
+            self.send(&WsMessage::Close(frame)).await?;
+        }
+
+        loop {
+            if let Some(close) = &self.close_received {
+                return Ok(close.clone());
+            }
+            if let WsMessage::Close(close) = self.recv().await? {
+                return Ok(close);
+            }
+        }
+    }
+
+    /// The server's close frame, once one has been received.
+    pub fn close_frame(&self) -> Option<&CloseFrame> {
+        self.close_received.as_ref()?.as_ref()
+    }
+}
+
+/// Encodes a single masked client frame.
+pub fn encode_frame(fin: bool, opcode: OpCode, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+
+    // FIN bit + opcode
+    frame.push(if fin { 0x80 } else { 0x00 } | opcode as u8);
+
+    // Mask bit (client must mask) + payload length
+    let len = payload.len();
+    if len < 126 {
+        frame.push(0x80 | len as u8);
+    } else if len <= 65535 {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        frame.push(0x80 | 127);
+        frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+
+    frame.extend_from_slice(&mask);
+
+    // Masked payload
+    for (i, &byte) in payload.iter().enumerate() {
+        frame.push(byte ^ mask[i % 4]);
+    }
+
+    frame
+}
+
+// Minimal base64 encode — only needs to handle the WebSocket key and accept hash
+const BASE64_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode(input: &[u8]) -> String {
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+
+    for chunk in input.chunks(3) {
+        let b0 = chunk[0] as u32;
+        let b1 = if chunk.len() > 1 { chunk[1] as u32 } else { 0 };
+        let b2 = if chunk.len() > 2 { chunk[2] as u32 } else { 0 };
+
+        let triple = (b0 << 16) | (b1 << 8) | b2;
+
+        output.push(BASE64_CHARS[((triple >> 18) & 0x3F) as usize] as char);
+        output.push(BASE64_CHARS[((triple >> 12) & 0x3F) as usize] as char);
+
+        if chunk.len() > 1 {
+            output.push(BASE64_CHARS[((triple >> 6) & 0x3F) as usize] as char);
+        } else {
+            output.push('=');
+        }
+
+        if chunk.len() > 2 {
+            output.push(BASE64_CHARS[(triple & 0x3F) as usize] as char);
+        } else {
+            output.push('=');
+        }
+    }
+
+    output
+}
@@ -0,0 +1,117 @@
+//! Stand-in servers for the integration tests.
+
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread::JoinHandle;
+
+/// Accepts a single connection on a random local port and hands it to `handler`.
+pub fn serve_once<T, F>(handler: F) -> (SocketAddr, JoinHandle<T>)
+where
+    F: FnOnce(TcpStream) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        handler(stream)
+    });
+    (addr, handle)
+}
+
+/// Reads a request head up to and including the blank line.
+pub fn read_request_head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    let mut b = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut b).unwrap();
+        head.push(b[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+pub fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.split("\r\n").find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        k.eq_ignore_ascii_case(name).then(|| v.trim())
+    })
+}
+
+/// Completes the server side of a WebSocket handshake with a correct accept key.
+pub fn accept_websocket(stream: &mut TcpStream) -> String {
+    accept_websocket_with(stream, "")
+}
+
+/// Like [`accept_websocket`], with `extra` appended to the response headers.
+pub fn accept_websocket_with(stream: &mut TcpStream, extra: &str) -> String {
+    let head = read_request_head(stream);
+    let key = header(&head, "sec-websocket-key").unwrap();
+    let resp = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\
+         {extra}\r\n",
+        sign_proto::ws::accept_key(key)
+    );
+    stream.write_all(resp.as_bytes()).unwrap();
+    head
+}
+
+/// Writes an unmasked server frame.
+pub fn write_frame(stream: &mut TcpStream, first_byte: u8, payload: &[u8]) {
+    let mut frame = vec![first_byte];
+    let len = payload.len();
+    if len < 126 {
+        frame.push(len as u8);
+    } else if len <= 65535 {
+        frame.push(126);
+        frame.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame).unwrap();
+}
+
+/// Reads a client frame, checks that it is masked and returns the first byte and
+/// unmasked payload.
+pub fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).unwrap();
+    assert!(header[1] & 0x80 != 0, "client frames must be masked");
+
+    let len = match header[1] & 0x7F {
+        126 => {
+            let mut buf = [0u8; 2];
+            stream.read_exact(&mut buf).unwrap();
+            u16::from_be_bytes(buf) as usize
+        }
+        127 => {
+            let mut buf = [0u8; 8];
+            stream.read_exact(&mut buf).unwrap();
+            u64::from_be_bytes(buf) as usize
+        }
+        n => n as usize,
+    };
+
+    let mut mask = [0u8; 4];
+    stream.read_exact(&mut mask).unwrap();
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).unwrap();
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+
+    (header[0], payload)
+}
+
+/// Deterministic stand-in for the hardware RNG.
+pub fn fill_random(buf: &mut [u8]) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = (i as u8).wrapping_mul(37).wrapping_add(11);
+    }
+}
@@ -0,0 +1,247 @@
+mod common;
+
+use std::net::{SocketAddr, TcpStream};
+
+use futures_lite::future::block_on;
+use miniz_oxide::deflate::core::{create_comp_flags_from_zip_params, CompressorOxide};
+use miniz_oxide::deflate::stream::deflate;
+use miniz_oxide::MZFlush;
+use sign_proto::deflate::{negotiate, DeflateConfig, DeflateParams};
+use sign_proto::ws::{
+    close_code, HandshakeError, HandshakeOptions, Limits, ProtocolError, WebSocket, WsMessage,
+};
+use url::Url;
+
+/// Server side of `permessage-deflate`: a raw deflate stream, sync-flushed after
+/// every message with the trailing empty block removed.
+struct Compressor(Box<CompressorOxide>);
+
+impl Compressor {
+    fn new() -> Self {
+        let flags = create_comp_flags_from_zip_params(6, -15, 0);
+        Self(Box::new(CompressorOxide::new(flags)))
+    }
+
+    fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0u8; data.len() + 64];
+        let result = deflate(&mut self.0, data, &mut out, MZFlush::Sync);
+        assert_eq!(result.bytes_consumed, data.len());
+        out.truncate(result.bytes_written);
+        assert!(out.ends_with(&[0x00, 0x00, 0xFF, 0xFF]));
+        out.truncate(out.len() - 4);
+        out
+    }
+}
+
+fn try_connect(addr: SocketAddr, deflate: DeflateConfig) -> anyhow::Result<WebSocket<TcpStream>> {
+    let url = Url::parse(&format!("ws://{addr}/sign/ws")).unwrap();
+    let conn = TcpStream::connect(addr).unwrap();
+    let options = HandshakeOptions {
+        deflate: Some(deflate),
+        ..Default::default()
+    };
+    block_on(WebSocket::handshake(
+        conn,
+        &url,
+        &options,
+        common::fill_random,
+    ))
+}
+
+#[test]
+fn offer_includes_configured_parameters() {
+    let config = DeflateConfig {
+        server_max_window_bits: Some(10),
+        server_no_context_takeover: true,
+    };
+    assert_eq!(
+        config.offer(),
+        "permessage-deflate; server_max_window_bits=10; server_no_context_takeover"
+    );
+    assert_eq!(DeflateConfig::default().offer(), "permessage-deflate");
+}
+
+#[test]
+fn negotiation_follows_rfc_7692() {
+    let config = DeflateConfig {
+        server_max_window_bits: Some(10),
+        server_no_context_takeover: false,
+    };
+
+    assert_eq!(
+        negotiate("permessage-deflate; server_max_window_bits=9", &config),
+        Ok(DeflateParams {
+            server_max_window_bits: 9,
+            server_no_context_takeover: false,
+        })
+    );
+    assert_eq!(
+        negotiate(
+            "permessage-deflate;server_no_context_takeover; client_no_context_takeover",
+            &config
+        ),
+        Ok(DeflateParams {
+            server_max_window_bits: 15,
+            server_no_context_takeover: true,
+        })
+    );
+
+    for bad in [
+        "x-webkit-deflate-frame",
+        "permessage-deflate; server_max_window_bits=12",
+        "permessage-deflate; server_max_window_bits=7",
+        "permessage-deflate; client_max_window_bits=10",
+        "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+        "permessage-deflate; mystery",
+        "permessage-deflate, permessage-deflate",
+    ] {
+        assert_eq!(
+            negotiate(bad, &config),
+            Err(HandshakeError::BadExtension(bad.to_string())),
+            "{bad}"
+        );
+    }
+}
+
+#[test]
+fn compressed_messages_with_context_takeover() {
+    let json = br#"{"type":"set_wifi","networks":[{"ssid":"PAL3.0","password":""}]}"#;
+    let (addr, server) = common::serve_once(move |mut stream| {
+        let head = common::accept_websocket_with(
+            &mut stream,
+            "Sec-WebSocket-Extensions: permessage-deflate\r\n",
+        );
+
+        let mut compressor = Compressor::new();
+        let first = compressor.compress(json);
+        // The second copy is mostly a back-reference into the first message
+        let second = compressor.compress(json);
+        assert!(second.len() < first.len());
+
+        common::write_frame(&mut stream, 0xC1, &first);
+        // Fragmented: RSV1 only on the first frame
+        let (a, b) = second.split_at(second.len() / 2);
+        common::write_frame(&mut stream, 0x41, a);
+        common::write_frame(&mut stream, 0x80, b);
+        common::write_frame(&mut stream, 0x81, b"plain");
+        head
+    });
+
+    let mut ws = try_connect(addr, DeflateConfig::default()).unwrap();
+    assert!(ws.is_compressed());
+
+    let expected = String::from_utf8(json.to_vec()).unwrap();
+    for _ in 0..2 {
+        let msg = block_on(ws.recv()).unwrap();
+        assert!(matches!(msg, WsMessage::Text(ref t) if *t == expected));
+    }
+    let msg = block_on(ws.recv()).unwrap();
+    assert!(matches!(msg, WsMessage::Text(ref t) if t == "plain"));
+
+    let head = server.join().unwrap();
+    assert_eq!(
+        common::header(&head, "sec-websocket-extensions"),
+        Some("permessage-deflate")
+    );
+}
+
+#[test]
+fn no_context_takeover_resets_between_messages() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::accept_websocket_with(
+            &mut stream,
+            "Sec-WebSocket-Extensions: permessage-deflate; server_no_context_takeover\r\n",
+        );
+        for text in [b"hello hello hello", b"world world world"] {
+            let payload = Compressor::new().compress(text);
+            common::write_frame(&mut stream, 0xC1, &payload);
+        }
+    });
+
+    let config = DeflateConfig {
+        server_no_context_takeover: true,
+        ..Default::default()
+    };
+    let mut ws = try_connect(addr, config).unwrap();
+    let first = block_on(ws.recv()).unwrap();
+    let second = block_on(ws.recv()).unwrap();
+    server.join().unwrap();
+
+    assert!(matches!(first, WsMessage::Text(ref t) if t == "hello hello hello"));
+    assert!(matches!(second, WsMessage::Text(ref t) if t == "world world world"));
+}
+
+#[test]
+fn decompression_bomb_is_rejected() {
+    let bomb = Compressor::new().compress(&vec![0u8; 1024 * 1024]);
+    assert!(bomb.len() < Limits::default().max_frame_size);
+
+    let (addr, server) = common::serve_once(move |mut stream| {
+        common::accept_websocket_with(
+            &mut stream,
+            "Sec-WebSocket-Extensions: permessage-deflate\r\n",
+        );
+        common::write_frame(&mut stream, 0xC2, &bomb);
+        common::read_frame(&mut stream)
+    });
+
+    let mut ws = try_connect(addr, DeflateConfig::default()).unwrap();
+    let error = block_on(ws.recv()).unwrap_err();
+    block_on(ws.flush()).unwrap();
+    let (opcode, close) = server.join().unwrap();
+
+    assert_eq!(
+        error.downcast::<ProtocolError>().unwrap(),
+        ProtocolError::MessageTooLarge {
+            max: Limits::default().max_message_size
+        }
+    );
+    assert_eq!(opcode, 0x88);
+    assert_eq!(&close[..2], &close_code::MESSAGE_TOO_BIG.to_be_bytes());
+}
+
+#[test]
+fn rsv1_on_control_frame_is_rejected() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::accept_websocket_with(
+            &mut stream,
+            "Sec-WebSocket-Extensions: permessage-deflate\r\n",
+        );
+        common::write_frame(&mut stream, 0xC9, b"");
+    });
+
+    let mut ws = try_connect(addr, DeflateConfig::default()).unwrap();
+    let error = block_on(ws.recv()).unwrap_err();
+    server.join().unwrap();
+
+    assert_eq!(
+        error.downcast::<ProtocolError>().unwrap(),
+        ProtocolError::ReservedBits
+    );
+}
+
+#[test]
+fn unrequested_extension_fails_handshake() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::accept_websocket_with(
+            &mut stream,
+            "Sec-WebSocket-Extensions: permessage-deflate\r\n",
+        )
+    });
+
+    let url = Url::parse(&format!("ws://{addr}/sign/ws")).unwrap();
+    let conn = TcpStream::connect(addr).unwrap();
+    let result = block_on(WebSocket::handshake(
+        conn,
+        &url,
+        &HandshakeOptions::default(),
+        common::fill_random,
+    ));
+    let head = server.join().unwrap();
+
+    assert_eq!(common::header(&head, "sec-websocket-extensions"), None);
+    assert_eq!(
+        result.err().unwrap().downcast::<HandshakeError>().unwrap(),
+        HandshakeError::BadExtension("permessage-deflate".to_string())
+    );
+}
@@ -0,0 +1,120 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use sign_proto::dns::{build_query, happy_eyeballs_order, parse_response, DnsError, RecordType};
+
+/// Turns a query into a response carrying `answers`, whose names point back at the question.
+fn respond(query: &[u8], rcode: u8, answers: &[(u16, u32, &[u8])]) -> Vec<u8> {
+    let mut msg = query.to_vec();
+    msg[2] |= 0x80;
+    msg[3] = 0x80 | rcode;
+    msg[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+
+    for (rtype, ttl, data) in answers {
+        msg.extend_from_slice(&[0xC0, 12]);
+        msg.extend_from_slice(&rtype.to_be_bytes());
+        msg.extend_from_slice(&1u16.to_be_bytes());
+        msg.extend_from_slice(&ttl.to_be_bytes());
+        msg.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        msg.extend_from_slice(data);
+    }
+    msg
+}
+
+#[test]
+fn query_encoding() {
+    let query = build_query(0xBEEF, "api.github.com", RecordType::Aaaa).unwrap();
+
+    assert_eq!(&query[..4], &[0xBE, 0xEF, 0x01, 0x00]);
+    assert_eq!(&query[4..12], &[0, 1, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(
+        &query[12..],
+        b"\x03api\x06github\x03com\x00\x00\x1c\x00\x01"
+    );
+}
+
+#[test]
+fn invalid_names_are_rejected() {
+    assert_eq!(
+        build_query(1, "a..b", RecordType::A),
+        Err(DnsError::InvalidName)
+    );
+    assert_eq!(
+        build_query(1, &"x".repeat(64), RecordType::A),
+        Err(DnsError::InvalidName)
+    );
+}
+
+#[test]
+fn parses_addresses_and_skips_cnames() {
+    let query = build_query(7, "api.purduehackers.com", RecordType::A).unwrap();
+    let cname = b"\x04edge\xC0\x10";
+    let v6 = Ipv6Addr::new(0x2606, 0x4700, 0, 0, 0, 0, 0, 0x1111);
+    let resp = respond(
+        &query,
+        0,
+        &[
+            (5, 600, cname),
+            (1, 300, &[104, 18, 2, 3]),
+            (28, 60, &v6.octets()),
+        ],
+    );
+
+    let records = parse_response(7, &resp).unwrap();
+
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].addr, IpAddr::V4(Ipv4Addr::new(104, 18, 2, 3)));
+    assert_eq!(records[0].ttl, 300);
+    assert_eq!(records[1].addr, IpAddr::V6(v6));
+    assert_eq!(records[1].ttl, 60);
+}
+
+#[test]
+fn error_responses() {
+    let query = build_query(9, "nope.example", RecordType::A).unwrap();
+
+    assert_eq!(
+        parse_response(9, &respond(&query, 3, &[])),
+        Err(DnsError::NxDomain)
+    );
+    assert_eq!(
+        parse_response(9, &respond(&query, 2, &[])),
+        Err(DnsError::ServerFailure(2))
+    );
+    assert_eq!(
+        parse_response(10, &respond(&query, 0, &[])),
+        Err(DnsError::IdMismatch)
+    );
+    assert_eq!(parse_response(9, &query), Err(DnsError::Malformed));
+
+    let mut truncated = respond(&query, 0, &[(1, 30, &[1, 2, 3, 4])]);
+    truncated.truncate(truncated.len() - 2);
+    assert_eq!(parse_response(9, &truncated), Err(DnsError::Malformed));
+}
+
+#[test]
+fn compression_loops_are_rejected() {
+    let query = build_query(3, "a.b", RecordType::A).unwrap();
+    let mut resp = respond(&query, 0, &[(1, 30, &[1, 2, 3, 4])]);
+    // Point the answer name at itself
+    let answer = query.len();
+    resp[answer] = 0xC0;
+    resp[answer + 1] = answer as u8;
+
+    assert_eq!(parse_response(3, &resp), Err(DnsError::Malformed));
+}
+
+#[test]
+fn happy_eyeballs_interleaves_families() {
+    let a = |n| IpAddr::V4(Ipv4Addr::new(10, 0, 0, n));
+    let b = |n| IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, n));
+
+    assert_eq!(
+        happy_eyeballs_order(&[a(1), a(2), a(3), b(1)]),
+        vec![b(1), a(1), a(2), a(3)]
+    );
+    assert_eq!(
+        happy_eyeballs_order(&[b(1), b(2), a(1)]),
+        vec![b(1), a(1), b(2)]
+    );
+    assert!(happy_eyeballs_order(&[]).is_empty());
+}
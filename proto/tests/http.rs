@@ -0,0 +1,189 @@
+mod common;
+
+use std::io::Write;
+use std::net::TcpStream;
+
+use futures_lite::future::block_on;
+use sign_proto::http::{read_body, read_head, write_request, BodyTooLarge, DEFAULT_MAX_BODY_SIZE};
+use url::Url;
+
+fn request(
+    addr: std::net::SocketAddr,
+    method: &str,
+    body: &[u8],
+) -> sign_proto::http::HttpResponse {
+    let url = Url::parse(&format!("http://{addr}/sign/provision?x=1")).unwrap();
+    let mut conn = TcpStream::connect(addr).unwrap();
+
+    block_on(async {
+        write_request(
+            &mut conn,
+            method,
+            &url,
+            &[("Content-Type", "application/json")],
+            body,
+        )
+        .await
+        .unwrap();
+        let mut resp = read_head(&mut conn).await.unwrap();
+        resp.body = read_body(&mut conn, &resp, DEFAULT_MAX_BODY_SIZE)
+            .await
+            .unwrap();
+        resp
+    })
+}
+
+#[test]
+fn post_with_content_length() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        let head = common::read_request_head(&mut stream);
+        let len: usize = common::header(&head, "content-length")
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut body = vec![0u8; len];
+        std::io::Read::read_exact(&mut stream, &mut body).unwrap();
+
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 13\r\nX-Test:  padded \r\n\r\n{\"key\":\"abc\"}")
+            .unwrap();
+        (head, body)
+    });
+
+    let resp = request(addr, "POST", b"{}");
+    let (head, body) = server.join().unwrap();
+
+    assert!(head.starts_with("POST /sign/provision?x=1 HTTP/1.1\r\n"));
+    assert_eq!(common::header(&head, "host"), Some("127.0.0.1"));
+    assert_eq!(
+        common::header(&head, "content-type"),
+        Some("application/json")
+    );
+    assert_eq!(body, b"{}");
+
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("x-test"), Some("padded"));
+    assert_eq!(resp.body, b"{\"key\":\"abc\"}");
+}
+
+#[test]
+fn get_reads_until_eof_without_content_length() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        let head = common::read_request_head(&mut stream);
+        stream.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").unwrap();
+        for _ in 0..3 {
+            stream.write_all(&[b'a'; 3000]).unwrap();
+        }
+        head
+    });
+
+    let resp = request(addr, "GET", b"");
+    let head = server.join().unwrap();
+
+    assert_eq!(common::header(&head, "content-length"), None);
+    assert_eq!(resp.status, 404);
+    assert_eq!(resp.body.len(), 9000);
+}
+
+/// Reads a response to a `GET /` with room for a body of at most `max_size` bytes.
+fn read_limited(addr: std::net::SocketAddr, max_size: usize) -> anyhow::Result<Vec<u8>> {
+    let url = Url::parse(&format!("http://{addr}/")).unwrap();
+    let mut conn = TcpStream::connect(addr).unwrap();
+    block_on(async {
+        write_request(&mut conn, "GET", &url, &[], b"")
+            .await
+            .unwrap();
+        let resp = read_head(&mut conn).await.unwrap();
+        read_body(&mut conn, &resp, max_size).await
+    })
+}
+
+#[test]
+fn oversized_content_length_is_rejected_before_reading() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::read_request_head(&mut stream);
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4294967296\r\n\r\n")
+            .unwrap();
+    });
+
+    let result = read_limited(addr, 1024);
+    server.join().unwrap();
+
+    let error = result.unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<BodyTooLarge>(),
+        Some(&BodyTooLarge { max: 1024 })
+    );
+}
+
+#[test]
+fn oversized_body_without_content_length_is_rejected() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::read_request_head(&mut stream);
+        stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+        let _ = stream.write_all(&[b'a'; 5000]);
+    });
+
+    let result = read_limited(addr, 4096);
+    server.join().unwrap();
+
+    assert!(result.unwrap_err().downcast_ref::<BodyTooLarge>().is_some());
+
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::read_request_head(&mut stream);
+        stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+        stream.write_all(&[b'a'; 4096]).unwrap();
+    });
+
+    let body = read_limited(addr, 4096).unwrap();
+    server.join().unwrap();
+    assert_eq!(body.len(), 4096);
+}
+
+#[test]
+fn oversized_headers_are_rejected() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::read_request_head(&mut stream);
+        stream.write_all(b"HTTP/1.1 200 OK\r\n").unwrap();
+        let _ = stream.write_all(&[b'x'; 20000]);
+    });
+
+    let url = Url::parse(&format!("http://{addr}/")).unwrap();
+    let mut conn = TcpStream::connect(addr).unwrap();
+    let result = block_on(async {
+        write_request(&mut conn, "GET", &url, &[], b"")
+            .await
+            .unwrap();
+        read_head(&mut conn).await
+    });
+    drop(conn);
+    server.join().unwrap();
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn rate_limit_headers() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::read_request_head(&mut stream);
+        stream
+            .write_all(
+                b"HTTP/1.1 403 Forbidden\r\n\
+                  ETag: W/\"abc\"\r\n\
+                  Retry-After: 120\r\n\
+                  X-RateLimit-Remaining: 0\r\n\
+                  X-RateLimit-Reset: 1760000000\r\n\
+                  Content-Length: 0\r\n\r\n",
+            )
+            .unwrap();
+    });
+
+    let resp = request(addr, "GET", b"");
+    server.join().unwrap();
+
+    assert_eq!(resp.status, 403);
+    assert_eq!(resp.etag(), Some("W/\"abc\""));
+    assert_eq!(resp.retry_after_secs(), Some(120));
+    assert_eq!(resp.rate_limit_reset(), Some(1_760_000_000));
+}
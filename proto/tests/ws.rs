@@ -0,0 +1,519 @@
+mod common;
+
+use std::net::TcpStream;
+
+use futures_lite::future::block_on;
+use sign_proto::ws::{
+    accept_key, close_code, decode_frame, CloseFrame, HandshakeError, HandshakeOptions, Limits,
+    ProtocolError, WebSocket, WsMessage,
+};
+use url::Url;
+
+fn try_connect(
+    addr: std::net::SocketAddr,
+    options: &HandshakeOptions,
+) -> anyhow::Result<WebSocket<TcpStream>> {
+    let url = Url::parse(&format!("ws://{addr}/sign/ws")).unwrap();
+    let conn = TcpStream::connect(addr).unwrap();
+    block_on(WebSocket::handshake(
+        conn,
+        &url,
+        options,
+        common::fill_random,
+    ))
+}
+
+fn connect(addr: std::net::SocketAddr) -> WebSocket<TcpStream> {
+    try_connect(addr, &HandshakeOptions::default()).unwrap()
+}
+
+fn handshake_error(result: anyhow::Result<WebSocket<TcpStream>>) -> HandshakeError {
+    result.err().unwrap().downcast::<HandshakeError>().unwrap()
+}
+
+#[test]
+fn handshake_sends_upgrade_request() {
+    let (addr, server) = common::serve_once(|mut stream| common::accept_websocket(&mut stream));
+
+    let _ws = connect(addr);
+    let head = server.join().unwrap();
+
+    assert!(head.starts_with("GET /sign/ws HTTP/1.1\r\n"));
+    assert_eq!(common::header(&head, "upgrade"), Some("websocket"));
+    assert_eq!(common::header(&head, "sec-websocket-version"), Some("13"));
+    assert_eq!(
+        common::header(&head, "sec-websocket-key").unwrap().len(),
+        24
+    );
+}
+
+#[test]
+fn handshake_rejects_non_101() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::read_request_head(&mut stream);
+        std::io::Write::write_all(&mut stream, b"HTTP/1.1 403 Forbidden\r\n\r\n").unwrap();
+    });
+
+    let result = try_connect(addr, &HandshakeOptions::default());
+    server.join().unwrap();
+
+    assert_eq!(handshake_error(result), HandshakeError::Status(403));
+}
+
+#[test]
+fn accept_key_rfc_6455_vector() {
+    // RFC 6455 section 1.3
+    assert_eq!(
+        accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+        "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+}
+
+#[test]
+fn sha1_vectors() {
+    use sign_proto::sha1::sha1;
+
+    let hex = |digest: [u8; 20]| {
+        digest
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>()
+    };
+
+    // FIPS 180-2 appendix A
+    assert_eq!(
+        hex(sha1(b"abc")),
+        "a9993e364706816aba3e25717850c26c9cd0d89d"
+    );
+    assert_eq!(
+        hex(sha1(
+            b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+        )),
+        "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+    );
+    assert_eq!(hex(sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+}
+
+#[test]
+fn handshake_rejects_wrong_accept() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::read_request_head(&mut stream);
+        std::io::Write::write_all(
+            &mut stream,
+            b"HTTP/1.1 101 Switching Protocols\r\n\
+              Upgrade: websocket\r\n\
+              Connection: Upgrade\r\n\
+              Sec-WebSocket-Accept: s3pPLMBiTxaQ9kK3gAiI5HtFjsE=\r\n\r\n",
+        )
+        .unwrap();
+    });
+
+    let result = try_connect(addr, &HandshakeOptions::default());
+    server.join().unwrap();
+
+    assert_eq!(handshake_error(result), HandshakeError::BadAccept);
+}
+
+#[test]
+fn handshake_rejects_missing_upgrade() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        let head = common::read_request_head(&mut stream);
+        let key = common::header(&head, "sec-websocket-key").unwrap();
+        let resp = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        );
+        std::io::Write::write_all(&mut stream, resp.as_bytes()).unwrap();
+    });
+
+    let result = try_connect(addr, &HandshakeOptions::default());
+    server.join().unwrap();
+
+    assert_eq!(handshake_error(result), HandshakeError::NotUpgraded);
+}
+
+#[test]
+fn subprotocol_and_custom_headers() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::accept_websocket_with(&mut stream, "Sec-WebSocket-Protocol: ph-sign.v1\r\n")
+    });
+
+    let options = HandshakeOptions {
+        protocols: vec!["ph-sign.v2".to_string(), "ph-sign.v1".to_string()],
+        headers: vec![("Authorization".to_string(), "Bearer abc123".to_string())],
+        ..Default::default()
+    };
+    let ws = try_connect(addr, &options).unwrap();
+    let head = server.join().unwrap();
+
+    assert_eq!(
+        common::header(&head, "sec-websocket-protocol"),
+        Some("ph-sign.v2, ph-sign.v1")
+    );
+    assert_eq!(
+        common::header(&head, "authorization"),
+        Some("Bearer abc123")
+    );
+    assert_eq!(ws.protocol(), Some("ph-sign.v1"));
+}
+
+#[test]
+fn unoffered_subprotocol_is_rejected() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::accept_websocket_with(&mut stream, "Sec-WebSocket-Protocol: chat\r\n")
+    });
+
+    let options = HandshakeOptions {
+        protocols: vec!["ph-sign.v1".to_string()],
+        ..Default::default()
+    };
+    let result = try_connect(addr, &options);
+    server.join().unwrap();
+
+    assert_eq!(
+        handshake_error(result),
+        HandshakeError::UnexpectedProtocol("chat".to_string())
+    );
+}
+
+#[test]
+fn no_subprotocol_selected() {
+    let (addr, server) = common::serve_once(|mut stream| common::accept_websocket(&mut stream));
+
+    let options = HandshakeOptions {
+        protocols: vec!["ph-sign.v1".to_string()],
+        ..Default::default()
+    };
+    let ws = try_connect(addr, &options).unwrap();
+    server.join().unwrap();
+
+    assert_eq!(ws.protocol(), None);
+}
+
+#[test]
+fn text_roundtrip_and_auto_pong() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::accept_websocket(&mut stream);
+        common::write_frame(&mut stream, 0x89, b"hb");
+        common::write_frame(&mut stream, 0x81, b"{\"type\":\"get_wifi\"}");
+
+        let pong = common::read_frame(&mut stream);
+        let reply = common::read_frame(&mut stream);
+        (pong, reply)
+    });
+
+    let mut ws = connect(addr);
+    let msg = block_on(ws.recv()).unwrap();
+    assert!(matches!(msg, WsMessage::Text(ref t) if t == "{\"type\":\"get_wifi\"}"));
+
+    let long = "x".repeat(300);
+    block_on(ws.send(&WsMessage::Text(long.clone()))).unwrap();
+
+    let (pong, reply) = server.join().unwrap();
+    assert_eq!(pong, (0x8A, b"hb".to_vec()));
+    assert_eq!(reply, (0x81, long.into_bytes()));
+}
+
+#[test]
+fn recv_queues_replies_until_flush() {
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+    let (checked_tx, checked_rx) = std::sync::mpsc::channel();
+    let (addr, server) = common::serve_once(move |mut stream| {
+        common::accept_websocket(&mut stream);
+        common::write_frame(&mut stream, 0x89, b"hb");
+        common::write_frame(&mut stream, 0x81, b"hi");
+
+        // Nothing may arrive until the client flushes
+        ready_rx.recv().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut byte = [0u8; 1];
+        let early = std::io::Read::read(&mut stream, &mut byte);
+        stream.set_nonblocking(false).unwrap();
+        checked_tx.send(()).unwrap();
+
+        (early.map_err(|e| e.kind()), common::read_frame(&mut stream))
+    });
+
+    let mut ws = connect(addr);
+    let msg = block_on(ws.recv()).unwrap();
+    ready_tx.send(()).unwrap();
+    checked_rx.recv().unwrap();
+    block_on(ws.flush()).unwrap();
+    let (early, pong) = server.join().unwrap();
+
+    assert!(matches!(msg, WsMessage::Text(ref t) if t == "hi"));
+    assert_eq!(early, Err(std::io::ErrorKind::WouldBlock));
+    assert_eq!(pong, (0x8A, b"hb".to_vec()));
+}
+
+#[test]
+fn binary_with_64_bit_length() {
+    let payload = vec![7u8; 70_000];
+    let expected = payload.clone();
+    let (addr, server) = common::serve_once(move |mut stream| {
+        common::accept_websocket(&mut stream);
+        common::write_frame(&mut stream, 0x82, &payload);
+    });
+
+    let mut ws = connect(addr);
+    ws.set_limits(Limits {
+        max_frame_size: 128 * 1024,
+        max_message_size: 128 * 1024,
+    });
+    let msg = block_on(ws.recv()).unwrap();
+    server.join().unwrap();
+
+    assert!(matches!(msg, WsMessage::Binary(ref b) if *b == expected));
+}
+
+#[test]
+fn fragmented_message_with_interleaved_ping() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::accept_websocket(&mut stream);
+        common::write_frame(&mut stream, 0x01, b"{\"type\":");
+        common::write_frame(&mut stream, 0x89, b"mid");
+        common::write_frame(&mut stream, 0x00, b"\"get_");
+        common::write_frame(&mut stream, 0x80, b"wifi\"}");
+        common::write_frame(&mut stream, 0x82, &[1, 2, 3]);
+
+        common::read_frame(&mut stream)
+    });
+
+    let mut ws = connect(addr);
+    let first = block_on(ws.recv()).unwrap();
+    let second = block_on(ws.recv()).unwrap();
+    block_on(ws.flush()).unwrap();
+    let pong = server.join().unwrap();
+
+    assert!(matches!(first, WsMessage::Text(ref t) if t == "{\"type\":\"get_wifi\"}"));
+    assert!(matches!(second, WsMessage::Binary(ref b) if b == &[1, 2, 3]));
+    assert_eq!(pong, (0x8A, b"mid".to_vec()));
+}
+
+#[test]
+fn utf8_split_across_fragments() {
+    let text = "⚡ bolt";
+    let bytes = text.as_bytes();
+    let (addr, server) = common::serve_once(move |mut stream| {
+        common::accept_websocket(&mut stream);
+        common::write_frame(&mut stream, 0x01, &bytes[..1]);
+        common::write_frame(&mut stream, 0x80, &bytes[1..]);
+    });
+
+    let mut ws = connect(addr);
+    let msg = block_on(ws.recv()).unwrap();
+    server.join().unwrap();
+
+    assert!(matches!(msg, WsMessage::Text(ref t) if t == text));
+}
+
+#[test]
+fn continuation_without_start_is_an_error() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::accept_websocket(&mut stream);
+        common::write_frame(&mut stream, 0x80, b"orphan");
+    });
+
+    let mut ws = connect(addr);
+    let result = block_on(ws.recv());
+    server.join().unwrap();
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn new_message_inside_fragmented_message_is_an_error() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::accept_websocket(&mut stream);
+        common::write_frame(&mut stream, 0x01, b"first");
+        common::write_frame(&mut stream, 0x81, b"second");
+    });
+
+    let mut ws = connect(addr);
+    let result = block_on(ws.recv());
+    server.join().unwrap();
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn fragmented_control_frame_is_an_error() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::accept_websocket(&mut stream);
+        common::write_frame(&mut stream, 0x09, b"ping");
+    });
+
+    let mut ws = connect(addr);
+    let result = block_on(ws.recv());
+    server.join().unwrap();
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn large_messages_are_sent_as_fragments() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::accept_websocket(&mut stream);
+        (0..4)
+            .map(|_| common::read_frame(&mut stream))
+            .collect::<Vec<_>>()
+    });
+
+    let mut ws = connect(addr);
+    ws.set_max_fragment_size(Some(4));
+    block_on(ws.send(&WsMessage::Text("abcdefghij".to_string()))).unwrap();
+    block_on(ws.send(&WsMessage::Ping(b"not split".to_vec()))).unwrap();
+    let frames = server.join().unwrap();
+
+    assert_eq!(
+        frames,
+        vec![
+            (0x01, b"abcd".to_vec()),
+            (0x00, b"efgh".to_vec()),
+            (0x80, b"ij".to_vec()),
+            (0x89, b"not split".to_vec()),
+        ]
+    );
+}
+
+#[test]
+fn server_close_is_echoed() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::accept_websocket(&mut stream);
+        let mut payload = close_code::SERVICE_RESTART.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"deploying");
+        common::write_frame(&mut stream, 0x88, &payload);
+        common::read_frame(&mut stream)
+    });
+
+    let mut ws = connect(addr);
+    let msg = block_on(ws.recv()).unwrap();
+    block_on(ws.flush()).unwrap();
+    let echo = server.join().unwrap();
+
+    let expected = CloseFrame::new(close_code::SERVICE_RESTART, "deploying");
+    assert!(matches!(msg, WsMessage::Close(Some(ref c)) if *c == expected));
+    assert_eq!(
+        echo,
+        (0x88, close_code::SERVICE_RESTART.to_be_bytes().to_vec())
+    );
+    assert_eq!(ws.close_frame(), Some(&expected));
+    assert!(block_on(ws.send(&WsMessage::Text("late".into()))).is_err());
+}
+
+#[test]
+fn client_close_waits_for_server_close() {
+    let (addr, server) = common::serve_once(|mut stream| {
+        common::accept_websocket(&mut stream);
+        let close = common::read_frame(&mut stream);
+        // Data still in flight before the server's close is discarded
+        common::write_frame(&mut stream, 0x81, b"late");
+        common::write_frame(&mut stream, 0x88, &close.1[..2]);
+        close
+    });
+
+    let mut ws = connect(addr);
+    let reply = block_on(ws.close(Some(CloseFrame::new(close_code::NORMAL, "bye")))).unwrap();
+    let close = server.join().unwrap();
+
+    let mut expected = close_code::NORMAL.to_be_bytes().to_vec();
+    expected.extend_from_slice(b"bye");
+    assert_eq!(close, (0x88, expected));
+    assert_eq!(reply, Some(CloseFrame::new(close_code::NORMAL, "")));
+}
+
+#[test]
+fn close_frame_parsing() {
+    assert_eq!(CloseFrame::parse(&[]).unwrap(), None);
+    assert!(CloseFrame::parse(&[0x03]).is_err());
+    // 1005 is reserved for "no status" and must not be sent
+    assert!(CloseFrame::parse(&1005u16.to_be_bytes()).is_err());
+    assert!(CloseFrame::parse(&[0x03, 0xE8, 0xFF]).is_err());
+
+    let long = CloseFrame::new(close_code::NORMAL, "é".repeat(100));
+    let encoded = long.encode();
+    assert!(encoded.len() <= 125);
+    assert!(CloseFrame::parse(&encoded).is_ok());
+}
+
+/// Sends `raw` after the handshake and returns the client's error and its close frame.
+fn reject(raw: &'static [u8], limits: Limits) -> (ProtocolError, (u8, Vec<u8>)) {
+    let (addr, server) = common::serve_once(move |mut stream| {
+        common::accept_websocket(&mut stream);
+        std::io::Write::write_all(&mut stream, raw).unwrap();
+        common::read_frame(&mut stream)
+    });
+
+    let mut ws = connect(addr);
+    ws.set_limits(limits);
+    let error = block_on(ws.recv()).unwrap_err();
+    block_on(ws.flush()).unwrap();
+    let close = server.join().unwrap();
+
+    (error.downcast::<ProtocolError>().unwrap(), close)
+}
+
+fn close_code_of(close: &(u8, Vec<u8>)) -> u16 {
+    assert_eq!(close.0, 0x88);
+    u16::from_be_bytes([close.1[0], close.1[1]])
+}
+
+#[test]
+fn huge_length_is_rejected_before_buffering() {
+    // Claims a 1 TiB payload, none of which follows
+    let (error, close) = reject(&[0x82, 127, 0, 0, 1, 0, 0, 0, 0, 0], Limits::default());
+
+    assert!(matches!(error, ProtocolError::FrameTooLarge { len, .. } if len == 1 << 40));
+    assert_eq!(close_code_of(&close), close_code::MESSAGE_TOO_BIG);
+}
+
+#[test]
+fn fragmented_message_over_limit_is_rejected() {
+    let limits = Limits {
+        max_frame_size: 4,
+        max_message_size: 6,
+    };
+    let (error, close) = reject(b"\x02\x04abcd\x80\x04efgh", limits);
+
+    assert_eq!(error, ProtocolError::MessageTooLarge { max: 6 });
+    assert_eq!(close_code_of(&close), close_code::MESSAGE_TOO_BIG);
+}
+
+#[test]
+fn masked_server_frame_is_rejected() {
+    let (error, close) = reject(b"\x81\x82\x00\x00\x00\x00hi", Limits::default());
+
+    assert_eq!(error, ProtocolError::MaskedFrame);
+    assert_eq!(close_code_of(&close), close_code::PROTOCOL_ERROR);
+}
+
+#[test]
+fn reserved_bits_are_rejected() {
+    let (error, close) = reject(b"\xC1\x02hi", Limits::default());
+
+    assert_eq!(error, ProtocolError::ReservedBits);
+    assert_eq!(close_code_of(&close), close_code::PROTOCOL_ERROR);
+}
+
+#[test]
+fn invalid_utf8_is_rejected() {
+    let (error, close) = reject(b"\x81\x02\xC3\x28", Limits::default());
+
+    assert_eq!(error, ProtocolError::InvalidUtf8);
+    assert_eq!(close_code_of(&close), close_code::INVALID_PAYLOAD);
+}
+
+#[test]
+fn decode_frame_waits_for_whole_frame() {
+    let frame = b"\x81\x7E\x00\x80";
+    let limits = Limits::default();
+
+    for len in 0..frame.len() {
+        assert_eq!(decode_frame(&frame[..len], &limits), Ok(None));
+    }
+    let mut full = frame.to_vec();
+    full.extend_from_slice(&[b'x'; 0x80]);
+    let (decoded, used) = decode_frame(&full, &limits).unwrap().unwrap();
+    assert_eq!(used, full.len());
+    assert_eq!(decoded.payload.len(), 0x80);
+}
@@ -0,0 +1,174 @@
+//! What the LEDs are currently showing, for status reports, and colors the server
+//! has asked to show instead of the clock. Whoever sets the LEDs to something other
+//! than the clock records it here.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+use embassy_time::{Duration, Instant};
+use palette::Srgb;
+
+use crate::Block;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum Mode {
+    Starting = 0,
+    /// Red
+    ConnectingWifi,
+    /// Purple
+    BleProvisioning,
+    /// Blue while checking, green while installing
+    Updating,
+    /// Lightning Time
+    Clock,
+    /// Colors set by the server
+    Override,
+    /// Orange
+    AuthRejected,
+    /// Amber
+    Reprovisioning,
+    /// Dim white
+    SafeMode,
+    /// Flashing white and magenta
+    Identify,
+}
+
+impl Mode {
+    const ALL: [Self; 10] = [
+        Self::Starting,
+        Self::ConnectingWifi,
+        Self::BleProvisioning,
+        Self::Updating,
+        Self::Clock,
+        Self::Override,
+        Self::AuthRejected,
+        Self::Reprovisioning,
+        Self::SafeMode,
+        Self::Identify,
+    ];
+}
+
+static MODE: AtomicU8 = AtomicU8::new(Mode::Starting as u8);
+
+pub fn set_mode(mode: Mode) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+pub fn mode() -> Mode {
+    Mode::ALL[MODE.load(Ordering::Relaxed) as usize]
+}
+
+/// Colors to show in place of the clock for a while.
+#[derive(Debug, Clone, Copy)]
+pub struct Override {
+    /// Color for each block, indexed by [`Block`]. `None` leaves the clock showing.
+    pub colors: [Option<Srgb<u8>>; 5],
+    /// How long to blend from what was showing to `colors`.
+    pub fade: Duration,
+    /// `None` keeps the override until it's cleared.
+    pub ttl: Option<Duration>,
+}
+
+struct ActiveOverride {
+    spec: Override,
+    started: Instant,
+    /// What was showing when the override started, captured on the first frame.
+    from: Option<[Srgb<u8>; 5]>,
+}
+
+static OVERRIDE: Mutex<Option<ActiveOverride>> = Mutex::new(None);
+
+/// Replaces any current override. Fades from whatever is showing at the time.
+pub fn set_override(spec: Override) {
+    *OVERRIDE.lock().unwrap() = Some(ActiveOverride {
+        spec,
+        started: Instant::now(),
+        from: None,
+    });
+}
+
+pub fn clear_override() {
+    *OVERRIDE.lock().unwrap() = None;
+}
+
+/// Colors for this frame given the clock's, or `None` if no override is active.
+/// `shown` is what each block shows now.
+pub fn override_frame(clock: &[Srgb<u8>; 5], shown: &[Srgb<u8>; 5]) -> Option<[Srgb<u8>; 5]> {
+    let mut active = OVERRIDE.lock().unwrap();
+    let current = active.as_mut()?;
+
+    let elapsed = current.started.elapsed();
+    if current.spec.ttl.is_some_and(|ttl| elapsed >= ttl) {
+        log::info!("Color override expired");
+        *active = None;
+        return None;
+    }
+
+    let from = *current.from.get_or_insert(*shown);
+    let progress = if elapsed >= current.spec.fade {
+        1.0
+    } else {
+        elapsed.as_millis() as f32 / current.spec.fade.as_millis() as f32
+    };
+
+    let mut frame = *clock;
+    for block in Block::ALL {
+        let i = block as usize;
+        if let Some(target) = current.spec.colors[i] {
+            frame[i] = blend(from[i], target, progress);
+        }
+    }
+    Some(frame)
+}
+
+/// Length of one step of the identify pattern.
+const IDENTIFY_STEP_MS: u64 = 150;
+/// Three white flashes, then a longer magenta one. Nothing else the sign shows looks
+/// like it.
+const IDENTIFY_PATTERN: [Srgb<u8>; 8] = [
+    Srgb::new(255, 255, 255),
+    Srgb::new(0, 0, 0),
+    Srgb::new(255, 255, 255),
+    Srgb::new(0, 0, 0),
+    Srgb::new(255, 255, 255),
+    Srgb::new(0, 0, 0),
+    Srgb::new(255, 0, 255),
+    Srgb::new(255, 0, 255),
+];
+
+/// When the identify pattern started and when it ends.
+static IDENTIFY: Mutex<Option<(Instant, Instant)>> = Mutex::new(None);
+
+/// Plays the identify pattern for `duration`, ahead of everything else.
+pub fn identify(duration: Duration) {
+    let now = Instant::now();
+    *IDENTIFY.lock().unwrap() = Some((now, now + duration));
+}
+
+/// One frame of the identify pattern: the color for every block and whether the
+/// button LED is lit. `None` once it's over.
+pub fn identify_frame() -> Option<(Srgb<u8>, bool)> {
+    let mut identify = IDENTIFY.lock().unwrap();
+    let (started, until) = (*identify)?;
+    if Instant::now() >= until {
+        *identify = None;
+        return None;
+    }
+
+    let step = (started.elapsed().as_millis() / IDENTIFY_STEP_MS) as usize;
+    Some((
+        IDENTIFY_PATTERN[step % IDENTIFY_PATTERN.len()],
+        step % 2 == 0,
+    ))
+}
+
+fn blend(from: Srgb<u8>, to: Srgb<u8>, progress: f32) -> Srgb<u8> {
+    let channel = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * progress).round() as u8;
+    Srgb::new(
+        channel(from.red, to.red),
+        channel(from.green, to.green),
+        channel(from.blue, to.blue),
+    )
+}
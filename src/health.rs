@@ -0,0 +1,158 @@
+//! Snapshot of how the device is doing, for the `get_status` command.
+
+use std::ffi::CStr;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+use build_time::build_time_utc;
+use esp_idf_svc::sys;
+use serde::Serialize;
+
+use crate::display;
+use crate::net::self_update::{self, UpdateCheck};
+use crate::net::ws;
+
+/// FreeRTOS handles of the long-lived threads, by name. Only threads that never exit
+/// may register, since nothing removes them.
+static TASKS: Mutex<Vec<(&'static str, usize)>> = Mutex::new(Vec::new());
+
+/// Includes the calling thread's stack in status reports.
+pub fn register_task(name: &'static str) {
+    let handle = unsafe { sys::xTaskGetCurrentTaskHandle() };
+    TASKS.lock().unwrap().push((name, handle as usize));
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceStatus {
+    pub firmware_version: &'static str,
+    pub build_time: &'static str,
+    pub uptime_secs: u64,
+    pub reset_reason: &'static str,
+    pub free_heap: u32,
+    pub min_free_heap: u32,
+    pub tasks: Vec<TaskStatus>,
+    /// `None` while not associated with an access point.
+    pub wifi: Option<WifiStatus>,
+    pub display_mode: display::Mode,
+    pub last_update_check: Option<UpdateCheck>,
+    pub sntp: &'static str,
+    pub server: ServerStatus,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TaskStatus {
+    pub name: &'static str,
+    /// Least free stack the task has ever had, in bytes.
+    pub stack_high_water: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WifiStatus {
+    pub ssid: String,
+    pub rssi: i8,
+    pub ip: Option<Ipv4Addr>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ServerStatus {
+    pub reconnects: u32,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub rtt_ms: Option<u64>,
+}
+
+pub fn device_status() -> DeviceStatus {
+    let connection = ws::status();
+
+    DeviceStatus {
+        firmware_version: env!("CARGO_PKG_VERSION"),
+        build_time: build_time_utc!(),
+        uptime_secs: unsafe { sys::esp_timer_get_time() } as u64 / 1_000_000,
+        reset_reason: reset_reason(),
+        free_heap: unsafe { sys::esp_get_free_heap_size() },
+        min_free_heap: unsafe { sys::esp_get_minimum_free_heap_size() },
+        tasks: task_statuses(),
+        wifi: wifi_status(),
+        display_mode: display::mode(),
+        last_update_check: self_update::last_check(),
+        sntp: sntp_status(),
+        server: ServerStatus {
+            reconnects: connection.reconnects,
+            consecutive_failures: connection.consecutive_failures,
+            last_error: connection.last_error,
+            rtt_ms: ws::last_rtt().map(|rtt| rtt.as_millis()),
+        },
+    }
+}
+
+fn task_statuses() -> Vec<TaskStatus> {
+    TASKS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|&(name, handle)| TaskStatus {
+            name,
+            // ESP-IDF's FreeRTOS counts stack in bytes, not words
+            stack_high_water: unsafe { sys::uxTaskGetStackHighWaterMark(handle as _) },
+        })
+        .collect()
+}
+
+fn reset_reason() -> &'static str {
+    #[allow(non_upper_case_globals)]
+    match unsafe { sys::esp_reset_reason() } {
+        sys::esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        sys::esp_reset_reason_t_ESP_RST_EXT => "external",
+        sys::esp_reset_reason_t_ESP_RST_SW => "software",
+        sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        sys::esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt_watchdog",
+        sys::esp_reset_reason_t_ESP_RST_TASK_WDT => "task_watchdog",
+        sys::esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        _ => "unknown",
+    }
+}
+
+pub(crate) fn wifi_status() -> Option<WifiStatus> {
+    let mut ap: sys::wifi_ap_record_t = unsafe { core::mem::zeroed() };
+    if unsafe { sys::esp_wifi_sta_get_ap_info(&mut ap) } != sys::ESP_OK {
+        return None;
+    }
+    let ssid = CStr::from_bytes_until_nul(&ap.ssid)
+        .map(|ssid| ssid.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    Some(WifiStatus {
+        ssid,
+        rssi: ap.rssi,
+        ip: sta_ip(),
+    })
+}
+
+fn sta_ip() -> Option<Ipv4Addr> {
+    unsafe {
+        let netif = sys::esp_netif_get_handle_from_ifkey(c"WIFI_STA_DEF".as_ptr());
+        if netif.is_null() {
+            return None;
+        }
+        let mut info: sys::esp_netif_ip_info_t = core::mem::zeroed();
+        if sys::esp_netif_get_ip_info(netif, &mut info) != sys::ESP_OK || info.ip.addr == 0 {
+            return None;
+        }
+        // Stored in network byte order
+        Some(Ipv4Addr::from(u32::from_be(info.ip.addr)))
+    }
+}
+
+fn sntp_status() -> &'static str {
+    #[allow(non_upper_case_globals)]
+    match unsafe { sys::sntp_get_sync_status() } {
+        sys::sntp_sync_status_t_SNTP_SYNC_STATUS_COMPLETED => "synced",
+        sys::sntp_sync_status_t_SNTP_SYNC_STATUS_IN_PROGRESS => "in_progress",
+        // The status resets to this once a sync has been applied, so tell that case
+        // apart by whether the clock looks right
+        _ if crate::net::unix_now().is_some() => "synced",
+        _ => "not_synced",
+    }
+}
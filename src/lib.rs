@@ -1,6 +1,11 @@
+pub mod display;
+pub mod health;
+pub mod logging;
 pub mod net;
 #[cfg(feature = "interactive")]
 pub mod printer;
+pub mod settings;
+pub mod telemetry;
 
 use anyhow::anyhow;
 use esp_idf_svc::{hal::ledc::LedcDriver, sys::EspError};
@@ -25,9 +30,12 @@ pub fn convert_error(e: EspError) -> anyhow::Error {
 
 pub struct Leds {
     channels: [LedcDriver<'static>; 15],
+    /// Last color set on each block, indexed by [`Block`].
+    shown: [palette::Srgb<u8>; 5],
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Block {
     Center = 0,
     BottomLeft,
@@ -44,6 +52,14 @@ enum Color {
 }
 
 impl Block {
+    pub const ALL: [Block; 5] = [
+        Block::Center,
+        Block::BottomLeft,
+        Block::BottomRight,
+        Block::Right,
+        Block::Top,
+    ];
+
     fn channel_for_color(&self, color: Color) -> usize {
         (*self as usize * 3) + color as usize
     }
@@ -66,10 +82,19 @@ const GAMMA_LUT: [u8; 256] = [
 
 impl Leds {
     pub fn create(channels: [LedcDriver<'static>; 15]) -> Leds {
-        Leds { channels }
+        Leds {
+            channels,
+            shown: [palette::Srgb::new(0, 0, 0); 5],
+        }
+    }
+
+    /// Last color set on each block, indexed by [`Block`].
+    pub fn colors(&self) -> [palette::Srgb<u8>; 5] {
+        self.shown
     }
 
     pub fn set_color(&mut self, color: palette::Srgb<u8>, block: Block) {
+        self.shown[block as usize] = color;
         let r = block.channel_for_color(Color::Red);
         self.channels[r]
             .set_duty(GAMMA_LUT[color.red as usize] as u32)
@@ -0,0 +1,182 @@
+//! Logger that writes to the UART like [`EspLogger`] and also keeps recent records in
+//! memory, so they can be fetched or streamed over the WebSocket.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Instant;
+use esp_idf_svc::log::EspLogger;
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+
+/// Approximate memory the ring buffer may use for record text.
+const BUFFER_BYTES: usize = 16 * 1024;
+/// Rough per-record overhead counted against [`BUFFER_BYTES`].
+const RECORD_OVERHEAD: usize = 32;
+/// Longest message kept; the UART still gets all of it.
+const MAX_MESSAGE_LEN: usize = 512;
+
+static LOGGER: SignLogger = SignLogger {
+    esp: EspLogger::new(),
+};
+
+static BUFFER: Mutex<RingBuffer> = Mutex::new(RingBuffer {
+    records: VecDeque::new(),
+    bytes: 0,
+});
+
+/// Most verbose level forwarded to the WebSocket, or [`LogLevel::Off`].
+static STREAM_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Off as u8);
+/// Records waiting for `ws_listen`. When it can't keep up, new records are dropped
+/// rather than slowing down whoever is logging.
+static STREAM: Channel<CriticalSectionRawMutex, LogRecord, 16> = Channel::new();
+
+/// Replaces `EspLogger::initialize_default`.
+pub fn init() {
+    log::set_logger(&LOGGER).unwrap();
+    LOGGER.esp.initialize();
+}
+
+/// Changes the most verbose level logged anywhere. Can't go past the level the
+/// firmware was built with.
+pub fn set_level(level: LogLevel) {
+    log::set_max_level(level.into());
+}
+
+pub fn level() -> LogLevel {
+    log::max_level().into()
+}
+
+/// Oldest first.
+pub fn recent() -> Vec<LogRecord> {
+    BUFFER.lock().unwrap().records.iter().cloned().collect()
+}
+
+/// Starts forwarding records at `level` or more severe to [`next_streamed`], or
+/// stops with [`LogLevel::Off`].
+pub fn stream(level: LogLevel) {
+    STREAM_LEVEL.store(level as u8, Ordering::Relaxed);
+    if level == LogLevel::Off {
+        while STREAM.try_receive().is_ok() {}
+    }
+}
+
+/// Waits for the next record to forward. Cancel-safe.
+pub async fn next_streamed() -> LogRecord {
+    STREAM.receive().await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum LogLevel {
+    Off = 0,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+impl From<LevelFilter> for LogLevel {
+    fn from(level: LevelFilter) -> Self {
+        match level {
+            LevelFilter::Off => LogLevel::Off,
+            LevelFilter::Error => LogLevel::Error,
+            LevelFilter::Warn => LogLevel::Warn,
+            LevelFilter::Info => LogLevel::Info,
+            LevelFilter::Debug => LogLevel::Debug,
+            LevelFilter::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogRecord {
+    /// Milliseconds since boot.
+    pub uptime_ms: u64,
+    pub level: LogLevel,
+    pub target: String,
+    pub message: String,
+}
+
+impl LogRecord {
+    fn size(&self) -> usize {
+        self.target.len() + self.message.len() + RECORD_OVERHEAD
+    }
+}
+
+struct RingBuffer {
+    records: VecDeque<LogRecord>,
+    bytes: usize,
+}
+
+impl RingBuffer {
+    fn push(&mut self, record: LogRecord) {
+        self.bytes += record.size();
+        self.records.push_back(record);
+        while self.bytes > BUFFER_BYTES {
+            match self.records.pop_front() {
+                Some(old) => self.bytes -= old.size(),
+                None => break,
+            }
+        }
+    }
+}
+
+struct SignLogger {
+    esp: EspLogger,
+}
+
+impl Log for SignLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.esp.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.esp.log(record);
+
+        let mut message = record.args().to_string();
+        if message.len() > MAX_MESSAGE_LEN {
+            let mut end = MAX_MESSAGE_LEN;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+        let entry = LogRecord {
+            uptime_ms: Instant::now().as_millis(),
+            level: record.level().to_level_filter().into(),
+            target: record.target().to_string(),
+            message,
+        };
+
+        if entry.level as u8 <= STREAM_LEVEL.load(Ordering::Relaxed) {
+            let _ = STREAM.try_send(entry.clone());
+        }
+        BUFFER.lock().unwrap().push(entry);
+    }
+
+    fn flush(&self) {
+        self.esp.flush();
+    }
+}
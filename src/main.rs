@@ -1,9 +1,8 @@
 #![feature(type_alias_impl_trait)]
 
 use build_time::build_time_utc;
-use chrono::{Datelike, Local, Timelike, Weekday};
-use chrono_tz::US::Eastern;
-use embassy_time::Timer;
+use chrono::{Datelike, Local, Timelike};
+use embassy_time::{Instant, Timer};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::{
@@ -21,14 +20,16 @@ use esp_idf_svc::{
 };
 use lightning_time::{LightningTime, LightningTimeColors};
 use log::info;
-use palette::rgb::Rgb;
+use palette::{rgb::Rgb, Srgb};
 use sign_firmware::{
     anyesp,
+    display::{self, Mode},
+    health,
     net::{
-        ble, connect_to_network, connect_to_network_with, provision_device, self_update, ws_listen,
-        DeviceConfig,
+        ble, commands::Event, connect_to_network, connect_to_network_with, events,
+        provision_device, scan, self_update, ws, ws_listen, DeviceConfig,
     },
-    Block, Leds,
+    settings, telemetry, Block, Leds,
 };
 
 extern crate alloc;
@@ -38,14 +39,21 @@ async fn wifi_connect(
     device_config: &mut DeviceConfig,
     leds: &mut Leds,
 ) {
+    display::set_mode(Mode::ConnectingWifi);
     leds.set_all_colors(Rgb::new(255, 0, 0)); // Red while connecting
     loop {
         match connect_to_network(wifi, device_config).await {
             Ok(()) => break,
             Err(e) => {
                 log::warn!("WiFi failed: {e}, starting BLE provisioning...");
+                display::set_mode(Mode::BleProvisioning);
                 leds.set_all_colors(Rgb::new(128, 0, 128)); // purple
-                match ble::ble_provision() {
+                let saved = device_config.get_wifi_networks();
+                let access_points = scan::scan(wifi, &saved).await.unwrap_or_else(|e| {
+                    log::warn!("WiFi scan failed: {e}");
+                    Vec::new()
+                });
+                match ble::ble_provision(&access_points) {
                     Ok(network) => {
                         device_config.add_wifi_network(&network).ok();
                         info!("Got WiFi creds via BLE, retrying...");
@@ -75,6 +83,7 @@ async fn wifi_reconnect(
         match connect_to_network_with(wifi, networks).await {
             Ok(()) => {
                 info!("WiFi reconnected!");
+                events::publish(Event::WifiReconnected);
                 return;
             }
             Err(e) => {
@@ -90,10 +99,9 @@ async fn amain(
     mut wifi: AsyncWifi<EspWifi<'static>>,
     mut device_config: DeviceConfig,
     #[allow(unused_variables)] button_switch: PinDriver<'static, Gpio36, Input>,
-    #[allow(unused_variables)]
-    #[allow(unused_mut)]
     mut button_led: PinDriver<'static, Gpio15, Output>,
 ) {
+    health::register_task("main");
     wifi_connect(&mut wifi, &mut device_config, &mut leds).await;
 
     // Provision device if needed
@@ -103,53 +111,89 @@ async fn amain(
 
     let config = std::sync::Arc::new(std::sync::Mutex::new(device_config));
 
-    // Spawn WebSocket listener thread if we have a device key
-    if let Some(key) = config.lock().unwrap().get_device_key() {
-        let ws_config = config.clone();
-        std::thread::Builder::new()
-            .stack_size(16_000)
-            .spawn(move || block_on(ws_listen(key, ws_config)))
-            .expect("ws listener thread");
-    }
+    // The listener retries provisioning itself if it failed above
+    spawn_ws_listener(&config);
 
     // Check for update
-    if let Err(e) = self_update(&mut leds).await {
-        log::warn!("Self-update check failed: {e}");
+    if settings::get_bool(settings::AUTO_UPDATE) {
+        if let Err(e) = self_update(&mut leds, &config).await {
+            log::warn!("Self-update check failed: {e}");
+        }
     }
 
     #[cfg(feature = "interactive")]
     let mut interactive_state = interactive::InteractiveState {
         last_led_change: Local::now(),
-        last_time: LightningTime::from(Local::now().with_timezone(&Eastern).time()),
+        last_time: LightningTime::from(Local::now().with_timezone(&settings::timezone()).time()),
         button_pressed: false,
     };
+    let mut identifying = false;
+    // Local date of the last weekly update check, so it runs once even if the loop
+    // misses the exact hour or passes through it many times
+    let mut last_weekly_check = None;
     loop {
         // Check WiFi connectivity and reconnect if needed
         if !wifi.wifi().is_connected().unwrap_or(false) {
             wifi_reconnect(&mut wifi, &config).await;
         }
 
-        let time = LightningTime::from(Local::now().with_timezone(&Eastern).time());
+        let time = LightningTime::from(Local::now().with_timezone(&settings::timezone()).time());
 
         #[cfg(feature = "interactive")]
         interactive::interactive(
             &mut interactive_state,
+            &config,
             &mut button_led,
             &button_switch,
             time,
         )
         .await;
 
-        set_colors(&time.colors(), &mut leds);
+        // Identifying takes over the whole sign until it's done
+        let frame_started = Instant::now();
+        if !show_identify(&mut leds, &mut button_led, &mut identifying) {
+            match ws::auth_state() {
+                ws::AuthState::Rejected(_) => {
+                    display::set_mode(Mode::AuthRejected);
+                    leds.set_all_colors(Rgb::new(255, 64, 0)); // orange
+                }
+                ws::AuthState::Reprovisioning => {
+                    display::set_mode(Mode::Reprovisioning);
+                    leds.set_all_colors(Rgb::new(255, 160, 0)); // amber
+                }
+                ws::AuthState::Pending | ws::AuthState::Accepted => {
+                    let clock = clock_colors(&time.colors());
+                    match display::override_frame(&clock, &leds.colors()) {
+                        Some(frame) => {
+                            display::set_mode(Mode::Override);
+                            set_colors(&frame, &mut leds);
+                        }
+                        None => {
+                            display::set_mode(Mode::Clock);
+                            set_colors(&clock, &mut leds);
+                        }
+                    }
+                }
+            }
+        }
+        telemetry::record_frame(frame_started.elapsed(), &leds.colors());
+
+        scan::serve_requested_scan(&mut wifi, &config).await;
+        if let Some(target) = self_update::take_requested_update() {
+            if let Err(e) = self_update::update(&mut leds, &config, target).await {
+                log::warn!("Requested update failed: {e}");
+            }
+        }
 
         // Weekly self-update check
-        if Local::now().weekday() == Weekday::Sat
-            && Local::now().hour() == 3
-            && Local::now().minute() == 0
-            && Local::now().second() == 0
-        {
-            if let Err(e) = self_update(&mut leds).await {
-                log::warn!("Weekly self-update check failed: {e}");
+        if let Some((day, hour)) = settings::update_schedule() {
+            let now = Local::now().with_timezone(&settings::timezone());
+            let today = now.date_naive();
+            if now.weekday() == day && now.hour() >= hour && last_weekly_check != Some(today) {
+                last_weekly_check = Some(today);
+                if let Err(e) = self_update(&mut leds, &config).await {
+                    log::warn!("Weekly self-update check failed: {e}");
+                }
             }
         }
 
@@ -170,6 +214,7 @@ mod interactive {
             last_time,
             button_pressed,
         }: &mut InteractiveState,
+        config: &std::sync::Arc<std::sync::Mutex<DeviceConfig>>,
         button_led: &mut PinDriver<'static, Gpio15, Output>,
         button_switch: &PinDriver<'static, Gpio36, Input>,
         time: LightningTime,
@@ -179,22 +224,29 @@ mod interactive {
         }
 
         if midnight(&time) && !midnight(last_time) {
-            if let Err(e) = printer::post_event(printer::PrinterEvent::Zero).await {
+            if let Err(e) = printer::post_event(printer::PrinterEvent::Zero, config).await {
                 log::error!("ZERO: Printer error: {e}");
             }
         } else if time.bolts != last_time.bolts {
-            if let Err(e) = printer::post_event(printer::PrinterEvent::NewBolt(time.bolts)).await {
+            if let Err(e) =
+                printer::post_event(printer::PrinterEvent::NewBolt(time.bolts), config).await
+            {
                 log::error!("BOLT: Printer error: {e}");
             }
         } else if time.zaps != last_time.zaps {
-            if let Err(e) = printer::post_event(printer::PrinterEvent::NewZap(time.zaps)).await {
+            if let Err(e) =
+                printer::post_event(printer::PrinterEvent::NewZap(time.zaps), config).await
+            {
                 log::error!("ZAP: Printer error: {e}");
             }
         }
 
         match (button_switch.get_level(), *button_pressed) {
             (Level::High, false) => {
-                if let Err(e) = printer::post_event(printer::PrinterEvent::ButtonPressed).await {
+                events::publish(Event::ButtonPressed);
+                if let Err(e) =
+                    printer::post_event(printer::PrinterEvent::ButtonPressed, config).await
+                {
                     log::error!("BUTTON PRESSED: Printer error: {e}");
                 }
                 *button_pressed = true;
@@ -220,15 +272,93 @@ mod interactive {
     }
 }
 
-fn set_colors(colors: &LightningTimeColors, leds: &mut Leds) {
-    leds.set_color(colors.bolt, Block::BottomLeft);
+/// Just enough to stay reachable so the server can fix things: WiFi and the server
+/// connection, but no clock, printer or update checks. Entered for a single boot by
+/// the `safe_mode` command.
+async fn safe_main(
+    mut leds: Leds,
+    mut wifi: AsyncWifi<EspWifi<'static>>,
+    mut device_config: DeviceConfig,
+    mut button_led: PinDriver<'static, Gpio15, Output>,
+) {
+    log::warn!("Booted into safe mode");
+    health::register_task("main");
+    wifi_connect(&mut wifi, &mut device_config, &mut leds).await;
+
+    display::set_mode(Mode::SafeMode);
+    leds.set_all_colors(Rgb::new(32, 32, 32));
+
+    let config = std::sync::Arc::new(std::sync::Mutex::new(device_config));
+    spawn_ws_listener(&config);
+
+    let mut identifying = false;
+    loop {
+        if !wifi.wifi().is_connected().unwrap_or(false) {
+            wifi_reconnect(&mut wifi, &config).await;
+        }
+        scan::serve_requested_scan(&mut wifi, &config).await;
+        let frame_started = Instant::now();
+        let was_identifying = identifying;
+        if !show_identify(&mut leds, &mut button_led, &mut identifying) && was_identifying {
+            display::set_mode(Mode::SafeMode);
+            leds.set_all_colors(Rgb::new(32, 32, 32));
+        }
+        telemetry::record_frame(frame_started.elapsed(), &leds.colors());
+        if let Some(target) = self_update::take_requested_update() {
+            if let Err(e) = self_update::update(&mut leds, &config, target).await {
+                log::warn!("Requested update failed: {e}");
+            }
+            display::set_mode(Mode::SafeMode);
+            leds.set_all_colors(Rgb::new(32, 32, 32));
+        }
+        Timer::after_millis(50).await;
+    }
+}
 
-    for block in [Block::Top, Block::Center] {
-        leds.set_color(colors.zap, block);
+/// Shows a frame of the identify pattern if it's playing, returning whether it is.
+/// `identifying` tracks whether it was, so the button LED is turned off once it ends.
+fn show_identify(
+    leds: &mut Leds,
+    button_led: &mut PinDriver<'static, Gpio15, Output>,
+    identifying: &mut bool,
+) -> bool {
+    match display::identify_frame() {
+        Some((color, button_lit)) => {
+            display::set_mode(Mode::Identify);
+            leds.set_all_colors(color);
+            button_led.set_level(button_lit.into()).unwrap();
+            *identifying = true;
+        }
+        None if *identifying => {
+            button_led.set_low().unwrap();
+            *identifying = false;
+        }
+        None => {}
     }
+    *identifying
+}
+
+fn spawn_ws_listener(config: &std::sync::Arc<std::sync::Mutex<DeviceConfig>>) {
+    let ws_config = config.clone();
+    std::thread::Builder::new()
+        .stack_size(16_000)
+        .spawn(move || block_on(ws_listen(ws_config)))
+        .expect("ws listener thread");
+}
 
+/// Lightning Time colors for each block, indexed by [`Block`].
+fn clock_colors(colors: &LightningTimeColors) -> [Srgb<u8>; 5] {
+    let mut frame = [colors.zap; 5];
+    frame[Block::BottomLeft as usize] = colors.bolt;
     for block in [Block::Right, Block::BottomRight] {
-        leds.set_color(colors.spark, block);
+        frame[block as usize] = colors.spark;
+    }
+    frame
+}
+
+fn set_colors(frame: &[Srgb<u8>; 5], leds: &mut Leds) {
+    for block in Block::ALL {
+        leds.set_color(frame[block as usize], block);
     }
 }
 
@@ -237,8 +367,9 @@ fn main() {
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
     esp_idf_svc::sys::link_patches();
 
-    // Bind the log crate to the ESP Logging facilities
-    esp_idf_svc::log::EspLogger::initialize_default();
+    // Bind the log crate to the ESP Logging facilities, keeping recent records for
+    // the server
+    sign_firmware::logging::init();
 
     info!(
         "Purdue Hackers Sign Firmware v.{}.{}.{} (Built {})",
@@ -259,7 +390,10 @@ fn main() {
     let nvs = EspDefaultNvsPartition::take().unwrap();
     let nvs_config = nvs.clone();
 
-    let device_config = DeviceConfig::new(nvs_config).expect("NVS config");
+    let mut device_config = DeviceConfig::new(nvs_config).expect("NVS config");
+    let safe_mode = device_config.take_safe_mode();
+    telemetry::init();
+    settings::load(&device_config);
 
     let wifi = AsyncWifi::wrap(
         EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs)).unwrap(),
@@ -397,7 +531,11 @@ fn main() {
                 sys::esp_vfs_eventfd_register(&sys::esp_vfs_eventfd_config_t { max_fds: 32 })
             })
             .unwrap();
-            block_on(amain(leds, wifi, device_config, button_switch, button_led))
+            if safe_mode {
+                block_on(safe_main(leds, wifi, device_config, button_led))
+            } else {
+                block_on(amain(leds, wifi, device_config, button_switch, button_led))
+            }
         })
         .unwrap()
         .join()
@@ -0,0 +1,173 @@
+//! Proof that a message comes from the holder of the device key, without the key
+//! ever leaving the device.
+//!
+//! A signature is the hex HMAC-SHA256, keyed with the device key, of these lines
+//! joined by `\n`: what is being signed (`ws` or `http`), a nonce, the Unix time and
+//! the device ID, then anything specific to the purpose. On the WebSocket the server
+//! picks the nonce. For HTTP requests the device picks it and appends the method,
+//! host, path and hex SHA-256 of the body, so the server has to reject nonces it has
+//! seen within its timestamp window.
+
+use esp_idf_svc::sys;
+use url::Url;
+
+use super::unix_now;
+use super::ws::fill_random;
+
+/// Nonces outside this length, or with anything but printable ASCII, are refused so
+/// a server can't get arbitrary data signed.
+const NONCE_LEN: core::ops::RangeInclusive<usize> = 16..=128;
+
+/// Hex factory MAC address. The server looks up the device key by it.
+pub fn device_id() -> String {
+    let mut mac = [0u8; 6];
+    unsafe {
+        sys::esp_efuse_mac_get_default(mac.as_mut_ptr());
+    }
+    hex(&mac)
+}
+
+/// A signature and everything besides the key that the server needs to check it.
+#[derive(Debug, Clone)]
+pub struct Signed {
+    pub device_id: String,
+    pub nonce: String,
+    pub timestamp: i64,
+    pub signature: String,
+}
+
+/// Signs the WebSocket challenge `nonce`.
+pub fn sign_challenge(key: &str, nonce: &str) -> anyhow::Result<Signed> {
+    sign(key, "ws", nonce, &[])
+}
+
+fn sign(key: &str, purpose: &str, nonce: &str, extra: &[&str]) -> anyhow::Result<Signed> {
+    if !NONCE_LEN.contains(&nonce.len()) || !nonce.bytes().all(|b| b.is_ascii_graphic()) {
+        anyhow::bail!("Refusing to sign malformed nonce");
+    }
+    let timestamp = unix_now().ok_or_else(|| anyhow::anyhow!("Clock not set yet, can't sign"))?;
+    let device_id = device_id();
+
+    let timestamp_str = timestamp.to_string();
+    let mut lines = vec![purpose, nonce, &timestamp_str, &device_id];
+    lines.extend_from_slice(extra);
+    let mac = hmac_sha256(key.as_bytes(), lines.join("\n").as_bytes())?;
+
+    Ok(Signed {
+        device_id,
+        nonce: nonce.to_string(),
+        timestamp,
+        signature: hex(&mac),
+    })
+}
+
+/// Headers authenticating one HTTP request.
+pub struct HttpSignature {
+    signed: Signed,
+    timestamp: String,
+}
+
+impl HttpSignature {
+    pub fn new(key: &str, method: &str, url: &Url, body: &[u8]) -> anyhow::Result<Self> {
+        let mut nonce = [0u8; 16];
+        fill_random(&mut nonce);
+        let body_hash = hex(&sha256(body)?);
+        let host = url.host_str().unwrap_or_default();
+
+        let signed = sign(
+            key,
+            "http",
+            &hex(&nonce),
+            &[method, host, url.path(), &body_hash],
+        )?;
+        Ok(Self {
+            timestamp: signed.timestamp.to_string(),
+            signed,
+        })
+    }
+
+    pub fn headers(&self) -> [(&'static str, &str); 4] {
+        [
+            ("X-Sign-Device", &self.signed.device_id),
+            ("X-Sign-Nonce", &self.signed.nonce),
+            ("X-Sign-Timestamp", &self.timestamp),
+            ("X-Sign-Signature", &self.signed.signature),
+        ]
+    }
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> anyhow::Result<[u8; 32]> {
+    let mut mac = [0u8; 32];
+    let result = unsafe {
+        let info = sys::mbedtls_md_info_from_type(sys::mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+        sys::mbedtls_md_hmac(
+            info,
+            key.as_ptr(),
+            key.len() as _,
+            message.as_ptr(),
+            message.len() as _,
+            mac.as_mut_ptr(),
+        )
+    };
+    if result != 0 {
+        anyhow::bail!("HMAC failed with code {result}");
+    }
+    Ok(mac)
+}
+
+fn sha256(data: &[u8]) -> anyhow::Result<[u8; 32]> {
+    let mut digest = [0u8; 32];
+    let result =
+        unsafe { sys::mbedtls_sha256(data.as_ptr(), data.len() as _, digest.as_mut_ptr(), 0) };
+    if result != 0 {
+        anyhow::bail!("SHA-256 failed with code {result}");
+    }
+    Ok(digest)
+}
+
+/// SHA-256 of data that arrives in pieces, such as a firmware download.
+pub(super) struct Sha256(Box<sys::mbedtls_sha256_context>);
+
+impl Sha256 {
+    pub fn new() -> anyhow::Result<Self> {
+        // Boxed so the context mbedtls was given never moves
+        let mut ctx = Box::new(unsafe { core::mem::zeroed::<sys::mbedtls_sha256_context>() });
+        let result = unsafe {
+            sys::mbedtls_sha256_init(&mut *ctx);
+            sys::mbedtls_sha256_starts(&mut *ctx, 0)
+        };
+        if result != 0 {
+            anyhow::bail!("SHA-256 failed with code {result}");
+        }
+        Ok(Self(ctx))
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let result =
+            unsafe { sys::mbedtls_sha256_update(&mut *self.0, data.as_ptr(), data.len() as _) };
+        if result != 0 {
+            anyhow::bail!("SHA-256 failed with code {result}");
+        }
+        Ok(())
+    }
+
+    /// The digest as lowercase hex.
+    pub fn finish(mut self) -> anyhow::Result<String> {
+        let mut digest = [0u8; 32];
+        let result = unsafe { sys::mbedtls_sha256_finish(&mut *self.0, digest.as_mut_ptr()) };
+        if result != 0 {
+            anyhow::bail!("SHA-256 failed with code {result}");
+        }
+        Ok(hex(&digest))
+    }
+}
+
+impl Drop for Sha256 {
+    fn drop(&mut self) {
+        unsafe { sys::mbedtls_sha256_free(&mut *self.0) };
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,42 @@
+use embassy_time::Duration;
+
+/// Exponential backoff with full jitter.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: u32,
+}
+
+impl Backoff {
+    pub const fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            multiplier: 2,
+        }
+    }
+
+    /// Upper bound of the delay before retry number `attempt` (starting at 0).
+    pub fn ceiling(&self, attempt: u32) -> Duration {
+        let factor = (self.multiplier as u64).saturating_pow(attempt);
+        let ms = self.initial.as_millis().saturating_mul(factor);
+        Duration::from_millis(ms.min(self.max.as_millis()))
+    }
+
+    /// Random delay in `[0, ceiling(attempt)]`.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        jitter(self.ceiling(attempt))
+    }
+}
+
+/// Random duration in `[0, max]`, for spreading out retries that would otherwise
+/// happen at the same moment across the fleet.
+pub fn jitter(max: Duration) -> Duration {
+    let max = max.as_millis();
+    if max == 0 {
+        return Duration::from_millis(0);
+    }
+    let random = unsafe { esp_idf_svc::sys::esp_random() } as u64;
+    Duration::from_millis(random % (max + 1))
+}
@@ -3,15 +3,22 @@ use log::info;
 use std::sync::mpsc;
 
 use super::config::WifiNetwork;
+use super::scan::AccessPoint;
 
 const SERVICE_UUID: esp32_nimble::utilities::BleUuid =
     uuid128!("d3a37e64-7a4c-4c3f-b929-1a5c0e4f7e30");
 const CHAR_UUID: esp32_nimble::utilities::BleUuid =
     uuid128!("d3a37e64-7a4c-4c3f-b929-1a5c0e4f7e31");
+/// Read-only JSON list of the access points seen just before provisioning started.
+const SCAN_CHAR_UUID: esp32_nimble::utilities::BleUuid =
+    uuid128!("d3a37e64-7a4c-4c3f-b929-1a5c0e4f7e32");
+/// Largest attribute value BLE allows.
+const MAX_ATTR_LEN: usize = 512;
 
 /// Start BLE advertising as "PH-Sign" and block until a phone/laptop writes
 /// WiFi credentials (`{"ssid":"...","password":"..."}`) to the GATT characteristic.
-pub fn ble_provision() -> anyhow::Result<WifiNetwork> {
+/// `access_points` can be read from a second characteristic to help pick a network.
+pub fn ble_provision(access_points: &[AccessPoint]) -> anyhow::Result<WifiNetwork> {
     info!("Starting BLE provisioning...");
 
     let ble_device = BLEDevice::take();
@@ -24,6 +31,12 @@ pub fn ble_provision() -> anyhow::Result<WifiNetwork> {
         NimbleProperties::WRITE | NimbleProperties::WRITE_NO_RSP,
     );
 
+    service
+        .lock()
+        .create_characteristic(SCAN_CHAR_UUID, NimbleProperties::READ)
+        .lock()
+        .set_value(&scan_json(access_points));
+
     let (tx, rx) = mpsc::sync_channel::<WifiNetwork>(1);
 
     characteristic.lock().on_write(move |args| {
@@ -69,3 +82,15 @@ pub fn ble_provision() -> anyhow::Result<WifiNetwork> {
     info!("BLE provisioning complete");
     Ok(network)
 }
+
+/// As many of the strongest access points as fit in one attribute.
+fn scan_json(access_points: &[AccessPoint]) -> Vec<u8> {
+    let mut count = access_points.len();
+    loop {
+        let json = serde_json::to_vec(&access_points[..count]).unwrap_or_default();
+        if json.len() <= MAX_ATTR_LEN || count == 0 {
+            return json;
+        }
+        count -= 1;
+    }
+}
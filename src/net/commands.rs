@@ -0,0 +1,547 @@
+//! Typed messages exchanged with the sign server over the WebSocket.
+//!
+//! Every command carries a `request_id` and gets exactly one reply with the same ID:
+//! a command-specific response, an `ack`, or an `error` with one of the stable
+//! [`ErrorCode`]s.
+
+use core::fmt;
+use std::collections::HashMap;
+
+use palette::Srgb;
+use serde::{Deserialize, Serialize};
+
+use crate::health::DeviceStatus;
+use crate::logging::{LogLevel, LogRecord};
+use crate::settings::{self, SettingInfo, Value};
+use crate::telemetry::Telemetry;
+use crate::Block;
+
+use super::auth::Signed;
+use super::config::WifiNetwork;
+use super::maintenance::Maintenance;
+use super::scan::AccessPoint;
+
+/// Version of the message formats in this module. Bumped when a command or response
+/// changes incompatibly; adding a command only adds to [`CAPABILITIES`].
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Commands this firmware understands, advertised during auth. Must list the tag of
+/// every [`Command`] variant.
+pub const CAPABILITIES: &[&str] = &[
+    "get_wifi",
+    "set_wifi",
+    "get_status",
+    "set_colors",
+    "clear_colors",
+    "reboot",
+    "factory_reset",
+    "safe_mode",
+    "trigger_update",
+    "get_logs",
+    "stream_logs",
+    "set_log_level",
+    "identify",
+    "scan_wifi",
+    "set_telemetry_interval",
+    "get_config",
+    "set_config",
+];
+
+/// Longest SSID and WPA passphrase the ESP-IDF WiFi driver accepts.
+const MAX_SSID_LEN: usize = 32;
+const MAX_PASSWORD_LEN: usize = 64;
+/// Limits on color overrides, so a typo can't leave a sign stuck for a year.
+const MAX_FADE_MS: u32 = 60_000;
+const MAX_TTL_SECS: u32 = 7 * 24 * 60 * 60;
+const MAX_IDENTIFY_SECS: u32 = 300;
+
+/// First message from the server on every connection.
+#[derive(Debug, Deserialize)]
+#[serde(from = "ChallengeMessage")]
+pub struct Challenge {
+    pub nonce: String,
+}
+
+/// Serde ignores `tag` when deserializing a struct, so the type is checked through
+/// an enum instead.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ChallengeMessage {
+    Challenge { nonce: String },
+}
+
+impl From<ChallengeMessage> for Challenge {
+    fn from(ChallengeMessage::Challenge { nonce }: ChallengeMessage) -> Self {
+        Self { nonce }
+    }
+}
+
+/// Answer to the [`Challenge`], proving we hold the device key without sending it.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename = "auth")]
+pub struct Auth<'a> {
+    pub device_id: &'a str,
+    pub nonce: &'a str,
+    pub timestamp: i64,
+    pub signature: &'a str,
+    pub protocol_version: u32,
+    pub firmware_version: &'static str,
+    pub capabilities: &'static [&'static str],
+}
+
+impl<'a> Auth<'a> {
+    pub fn new(signed: &'a Signed) -> Self {
+        Self {
+            device_id: &signed.device_id,
+            nonce: &signed.nonce,
+            timestamp: signed.timestamp,
+            signature: &signed.signature,
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: env!("CARGO_PKG_VERSION"),
+            capabilities: CAPABILITIES,
+        }
+    }
+}
+
+/// The server's answer to [`Auth`]. No commands arrive before it.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuthResult {
+    AuthOk {
+        protocol_version: u32,
+    },
+    /// The key is unknown or has been revoked.
+    AuthError {
+        #[serde(default)]
+        message: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    GetWifi,
+    SetWifi {
+        networks: Vec<WifiNetwork>,
+    },
+    GetStatus,
+    /// Shows fixed colors instead of the clock until `ttl_secs` pass, if given, or
+    /// `clear_colors` arrives.
+    SetColors {
+        /// Color for the whole sign.
+        color: Option<HexColor>,
+        /// Colors for individual blocks, taking precedence over `color`.
+        #[serde(default)]
+        blocks: HashMap<Block, HexColor>,
+        #[serde(default)]
+        fade_ms: u32,
+        ttl_secs: Option<u32>,
+    },
+    ClearColors,
+    Reboot,
+    /// Erases the stored configuration and restarts.
+    FactoryReset {
+        #[serde(default)]
+        keep_key: bool,
+    },
+    /// Restarts with only WiFi and the server connection running, for one boot.
+    SafeMode,
+    /// Checks for an update straight away, or installs a specific release or image.
+    /// Progress is reported with `update_*` events.
+    TriggerUpdate {
+        /// Release tag such as `v0.3.0`, installed even if older than this firmware.
+        version: Option<String>,
+        /// HTTPS URL of a firmware image, installed if it matches `sha256`.
+        url: Option<String>,
+        /// Hex SHA-256 of the image at `url`. Required with `url`, since nothing else
+        /// vouches for it.
+        sha256: Option<String>,
+    },
+    /// Returns the records kept in memory, oldest first.
+    GetLogs,
+    /// Sends new records at `level` or more severe as `log` messages until the
+    /// connection ends, or stops with `off`.
+    StreamLogs {
+        level: LogLevel,
+    },
+    /// Changes the most verbose level logged at all, until the next boot.
+    SetLogLevel {
+        level: LogLevel,
+    },
+    /// Flashes the whole sign and the button LED so it can be picked out.
+    Identify {
+        #[serde(default = "default_identify_secs")]
+        seconds: u32,
+    },
+    /// Lists nearby access points, marking saved networks. Acknowledged right away;
+    /// the result follows as a `wifi_scan` or `wifi_scan_failed` event.
+    ScanWifi,
+    /// Seconds between telemetry pushes, or 0 to only push after notable events.
+    SetTelemetryInterval {
+        seconds: u32,
+    },
+    GetConfig,
+    /// Changes any number of settings at once, all or none.
+    SetConfig {
+        settings: HashMap<String, Value>,
+    },
+}
+
+fn default_identify_secs() -> u32 {
+    10
+}
+
+impl Command {
+    /// What to do after replying, for commands that restart the device.
+    pub fn maintenance(&self) -> Option<Maintenance> {
+        match self {
+            Self::Reboot => Some(Maintenance::Reboot),
+            Self::FactoryReset { keep_key } => Some(Maintenance::FactoryReset {
+                keep_key: *keep_key,
+            }),
+            Self::SafeMode => Some(Maintenance::SafeMode),
+            _ => None,
+        }
+    }
+
+    /// Checks parameters that deserialize fine but can't be acted on.
+    fn validate(&self) -> Result<(), CommandError> {
+        match self {
+            Self::GetWifi
+            | Self::GetStatus
+            | Self::ClearColors
+            | Self::Reboot
+            | Self::FactoryReset { .. }
+            | Self::SafeMode
+            | Self::GetLogs
+            | Self::StreamLogs { .. }
+            | Self::SetLogLevel { .. }
+            | Self::ScanWifi
+            | Self::GetConfig => Ok(()),
+            Self::SetTelemetryInterval { seconds } => {
+                let value = Value::Int((*seconds).into());
+                settings::validate(settings::TELEMETRY_INTERVAL, &value)
+                    .map_err(|message| CommandError::new(ErrorCode::InvalidParams, message))
+            }
+            Self::SetConfig { settings } => {
+                for (key, value) in settings {
+                    settings::validate(key, value)
+                        .map_err(|message| CommandError::new(ErrorCode::InvalidParams, message))?;
+                }
+                Ok(())
+            }
+            Self::Identify { seconds } => {
+                if *seconds == 0 || *seconds > MAX_IDENTIFY_SECS {
+                    return Err(CommandError::new(
+                        ErrorCode::InvalidParams,
+                        format!("seconds must be 1 to {MAX_IDENTIFY_SECS}"),
+                    ));
+                }
+                Ok(())
+            }
+            Self::TriggerUpdate {
+                version,
+                url,
+                sha256,
+            } => {
+                let invalid =
+                    |message: &str| Err(CommandError::new(ErrorCode::InvalidParams, message));
+                if version.is_some() && url.is_some() {
+                    return invalid("Give a version or a url, not both");
+                }
+                if let Some(version) = version {
+                    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+');
+                    if version.is_empty() || version.len() > 64 || !version.chars().all(valid) {
+                        return invalid("Invalid release tag");
+                    }
+                }
+                if url.as_ref().is_some_and(|url| !url.starts_with("https://")) {
+                    return invalid("Firmware url must be https");
+                }
+                match (url, sha256) {
+                    (Some(_), None) => return invalid("A firmware url needs its sha256"),
+                    (None, Some(_)) => return invalid("sha256 only applies to a url"),
+                    _ => {}
+                }
+                let is_digest =
+                    |hex: &String| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit());
+                if sha256.as_ref().is_some_and(|hex| !is_digest(hex)) {
+                    return invalid("sha256 must be 64 hex digits");
+                }
+                Ok(())
+            }
+            Self::SetColors {
+                color,
+                blocks,
+                fade_ms,
+                ttl_secs,
+            } => {
+                if color.is_none() && blocks.is_empty() {
+                    return Err(CommandError::new(
+                        ErrorCode::InvalidParams,
+                        "Need a color or at least one block",
+                    ));
+                }
+                if *fade_ms > MAX_FADE_MS {
+                    return Err(CommandError::new(
+                        ErrorCode::InvalidParams,
+                        format!("fade_ms must be at most {MAX_FADE_MS}"),
+                    ));
+                }
+                if ttl_secs.is_some_and(|ttl| ttl == 0 || ttl > MAX_TTL_SECS) {
+                    return Err(CommandError::new(
+                        ErrorCode::InvalidParams,
+                        format!("ttl_secs must be 1 to {MAX_TTL_SECS}"),
+                    ));
+                }
+                Ok(())
+            }
+            Self::SetWifi { networks } => {
+                for network in networks {
+                    if network.ssid.is_empty() || network.ssid.len() > MAX_SSID_LEN {
+                        return Err(CommandError::new(
+                            ErrorCode::InvalidParams,
+                            format!("SSID must be 1 to {MAX_SSID_LEN} bytes"),
+                        ));
+                    }
+                    if network.password.len() > MAX_PASSWORD_LEN {
+                        return Err(CommandError::new(
+                            ErrorCode::InvalidParams,
+                            format!("Password for {} is too long", network.ssid),
+                        ));
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A color written as `"#rrggbb"`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct HexColor(pub Srgb<u8>);
+
+impl TryFrom<String> for HexColor {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("Invalid color {s:?}, expected \"#rrggbb\"");
+        let hex = s.strip_prefix('#').ok_or_else(invalid)?;
+        if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+        Ok(Self(Srgb::new(channel(0)?, channel(2)?, channel(4)?)))
+    }
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub request_id: String,
+    pub command: Command,
+}
+
+/// Parses and validates a command, or returns the error reply to send instead.
+pub fn parse_request(text: &str) -> Result<Request, Reply> {
+    let value: serde_json::Value = serde_json::from_str(text)
+        .map_err(|e| Reply::error(None, CommandError::new(ErrorCode::InvalidMessage, e)))?;
+
+    let Some(request_id) = value["request_id"].as_str().map(str::to_string) else {
+        return Err(Reply::error(
+            None,
+            CommandError::new(ErrorCode::InvalidMessage, "Missing request_id"),
+        ));
+    };
+
+    // Checked separately so that an old firmware can tell the dashboard a command is
+    // unknown rather than malformed
+    let kind = value["type"].as_str().unwrap_or_default();
+    if !CAPABILITIES.contains(&kind) {
+        return Err(Reply::error(
+            Some(request_id),
+            CommandError::new(
+                ErrorCode::UnknownCommand,
+                format!("Unknown command {kind:?}"),
+            ),
+        ));
+    }
+
+    let command = Command::deserialize(&value)
+        .map_err(|e| CommandError::new(ErrorCode::InvalidParams, e))
+        .and_then(|command| command.validate().map(|()| command));
+    match command {
+        Ok(command) => Ok(Request {
+            request_id,
+            command,
+        }),
+        Err(e) => Err(Reply::error(Some(request_id), e)),
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    /// The command was carried out and has nothing to report.
+    Ack,
+    WifiNetworks {
+        networks: Vec<WifiNetwork>,
+    },
+    Status(Box<DeviceStatus>),
+    Config {
+        settings: Vec<SettingInfo>,
+    },
+    Logs {
+        /// The current most verbose level logged.
+        level: LogLevel,
+        records: Vec<LogRecord>,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+impl From<CommandError> for Response {
+    fn from(e: CommandError) -> Self {
+        Self::Error {
+            code: e.code,
+            message: e.message,
+        }
+    }
+}
+
+/// A [`Response`] addressed to the request it answers.
+#[derive(Debug, Serialize)]
+pub struct Reply {
+    /// `None` only when the request was too broken to have one.
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub response: Response,
+}
+
+impl Reply {
+    pub fn new(request_id: String, response: Response) -> Self {
+        Self {
+            request_id: Some(request_id),
+            response,
+        }
+    }
+
+    pub fn error(request_id: Option<String>, error: CommandError) -> Self {
+        Self {
+            request_id,
+            response: error.into(),
+        }
+    }
+}
+
+/// Something the server should hear about without having asked.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    ButtonPressed,
+    WifiReconnected,
+    /// `version` is `None` for updates straight from a URL.
+    UpdateStarted {
+        version: Option<String>,
+    },
+    UpdateProgress {
+        version: Option<String>,
+        bytes: u64,
+        /// `None` if the server didn't say how big the image is.
+        total: Option<u64>,
+    },
+    /// Sent just before restarting into the new firmware.
+    UpdateInstalled {
+        version: Option<String>,
+    },
+    UpdateFailed {
+        version: Option<String>,
+        error: String,
+    },
+    /// An update check found nothing newer.
+    UpToDate {
+        version: String,
+    },
+    /// Result of `scan_wifi`, strongest first.
+    WifiScan {
+        access_points: Vec<AccessPoint>,
+    },
+    WifiScanFailed {
+        error: String,
+    },
+}
+
+impl Event {
+    /// Whether the dashboard should get fresh telemetry along with this event.
+    pub fn is_notable(&self) -> bool {
+        matches!(
+            self,
+            Self::ButtonPressed | Self::WifiReconnected | Self::UpdateFailed { .. }
+        )
+    }
+}
+
+/// An [`Event`] stamped with when it happened, since it may be delivered much later
+/// if the server was unreachable.
+#[derive(Debug, Clone, Serialize)]
+pub struct EventMessage {
+    #[serde(flatten)]
+    pub event: Event,
+    /// Unix time, or `None` if the clock wasn't set yet.
+    pub at: Option<i64>,
+}
+
+/// A record forwarded by `stream_logs`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename = "log")]
+pub struct LogMessage<'a> {
+    #[serde(flatten)]
+    pub record: &'a LogRecord,
+}
+
+/// Sent every telemetry interval, and after notable events.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename = "telemetry")]
+pub struct TelemetryMessage {
+    #[serde(flatten)]
+    pub telemetry: Telemetry,
+    pub at: Option<i64>,
+}
+
+/// Error codes sent in `error` replies. The dashboard matches on these, so existing
+/// ones must never be renamed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Not JSON, or missing `request_id`.
+    InvalidMessage,
+    /// This firmware doesn't know the command; see the capabilities sent in auth.
+    UnknownCommand,
+    /// The command is known but its parameters are missing or invalid.
+    InvalidParams,
+    /// The command was valid but failed on the device.
+    Internal,
+}
+
+#[derive(Debug)]
+pub struct CommandError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl CommandError {
+    pub fn new(code: ErrorCode, message: impl fmt::Display) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for CommandError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(ErrorCode::Internal, e)
+    }
+}
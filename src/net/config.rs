@@ -1,10 +1,22 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+
 use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys;
 use log::info;
 use serde::{Deserialize, Serialize};
 
+use crate::anyesp;
+use crate::settings::Value;
+
+use super::self_update::ReleaseCache;
+
 const NVS_NAMESPACE: &str = "sign_cfg";
 const KEY_DEVICE_KEY: &str = "device_key";
 const KEY_WIFI_NETWORKS: &str = "wifi_nets";
+const KEY_RELEASE_CACHE: &str = "release_cache";
+const KEY_SAFE_MODE: &str = "safe_mode";
+const KEY_SETTINGS: &str = "settings";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WifiNetwork {
@@ -56,6 +68,12 @@ impl DeviceConfig {
         Ok(())
     }
 
+    pub fn clear_device_key(&mut self) -> anyhow::Result<()> {
+        self.nvs.remove(KEY_DEVICE_KEY)?;
+        info!("Device key removed from NVS");
+        Ok(())
+    }
+
     pub fn get_wifi_networks(&self) -> Vec<WifiNetwork> {
         let mut buf = [0u8; 2048];
         let blob = match self.nvs.get_blob(KEY_WIFI_NETWORKS, &mut buf) {
@@ -77,4 +95,78 @@ impl DeviceConfig {
         networks.push(network.clone());
         self.set_wifi_networks(&networks)
     }
+
+    pub fn get_release_cache(&self) -> Option<ReleaseCache> {
+        let mut buf = [0u8; 2048];
+        let blob = self
+            .nvs
+            .get_blob(KEY_RELEASE_CACHE, &mut buf)
+            .ok()
+            .flatten()?;
+        serde_json::from_slice(blob).ok()
+    }
+
+    pub fn set_release_cache(&mut self, cache: &ReleaseCache) -> anyhow::Result<()> {
+        let json = serde_json::to_vec(cache)?;
+        self.nvs.set_blob(KEY_RELEASE_CACHE, &json)?;
+        Ok(())
+    }
+
+    pub fn set_safe_mode(&mut self, enabled: bool) -> anyhow::Result<()> {
+        self.nvs.set_u8(KEY_SAFE_MODE, enabled.into())?;
+        Ok(())
+    }
+
+    /// Settings changed from their defaults, see [`crate::settings`].
+    pub fn get_settings(&self) -> HashMap<String, Value> {
+        let mut buf = [0u8; 2048];
+        let blob = match self.nvs.get_blob(KEY_SETTINGS, &mut buf) {
+            Ok(Some(data)) => data.to_vec(),
+            _ => return HashMap::new(),
+        };
+        serde_json::from_slice(&blob).unwrap_or_default()
+    }
+
+    pub fn set_settings(&mut self, settings: &BTreeMap<&str, Value>) -> anyhow::Result<()> {
+        let json = serde_json::to_vec(settings)?;
+        self.nvs.set_blob(KEY_SETTINGS, &json)?;
+        Ok(())
+    }
+
+    /// Whether to boot into safe mode, clearing the flag so only one boot does.
+    pub fn take_safe_mode(&mut self) -> bool {
+        let enabled = matches!(self.nvs.get_u8(KEY_SAFE_MODE), Ok(Some(1)));
+        if enabled {
+            if let Err(e) = self.set_safe_mode(false) {
+                log::error!("Failed to clear safe mode: {e}");
+            }
+        }
+        enabled
+    }
+
+    /// Erases everything this namespace holds, whichever firmware wrote it, then puts
+    /// the device key back if `keep_key`.
+    pub fn factory_reset(&mut self, keep_key: bool) -> anyhow::Result<()> {
+        let device_key = self.get_device_key().filter(|_| keep_key);
+
+        let namespace = CString::new(NVS_NAMESPACE)?;
+        unsafe {
+            let mut handle: sys::nvs_handle_t = 0;
+            anyesp!(sys::nvs_open(
+                namespace.as_ptr(),
+                sys::nvs_open_mode_t_NVS_READWRITE,
+                &mut handle
+            ))?;
+            let erased =
+                anyesp!(sys::nvs_erase_all(handle)).and_then(|()| anyesp!(sys::nvs_commit(handle)));
+            sys::nvs_close(handle);
+            erased?;
+        }
+        info!("Configuration erased");
+
+        if let Some(key) = device_key {
+            self.set_device_key(&key)?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,226 @@
+use core::fmt;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::Mutex;
+
+use async_io_mini::Async;
+use embassy_time::{with_deadline, Duration, Instant};
+use esp_idf_svc::sys;
+use log::{info, warn};
+use sign_proto::dns::{build_query, parse_response, DnsError, Record, RecordType};
+
+/// How long to wait for each server before trying the next one.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+/// TTLs are clamped to this range, so that a zero TTL doesn't defeat the cache and
+/// a huge one doesn't pin a stale address until reboot.
+const MIN_TTL_SECS: u32 = 30;
+const MAX_TTL_SECS: u32 = 60 * 60 * 6;
+/// lwIP keeps this many DNS servers, filled in by DHCP.
+const MAX_DNS_SERVERS: u8 = 2;
+
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+static CACHE: Mutex<BTreeMap<String, CacheEntry>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug)]
+pub enum ResolveError {
+    /// DHCP hasn't given us a DNS server.
+    NoServers,
+    /// No server answered in time.
+    Timeout {
+        host: String,
+    },
+    Io(std::io::Error),
+    /// A server answered with an error or without any addresses.
+    Dns {
+        host: String,
+        error: DnsError,
+    },
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoServers => write!(f, "No DNS servers configured"),
+            Self::Timeout { host } => write!(f, "DNS lookup for {host} timed out"),
+            Self::Io(e) => write!(f, "DNS socket error: {e}"),
+            Self::Dns { host, error } => write!(f, "DNS lookup for {host} failed: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+impl From<std::io::Error> for ResolveError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Resolves `host` to its IPv6 and IPv4 addresses, using the cache while the
+/// records' TTL allows. IP literals are returned as they are.
+pub async fn resolve(host: &str) -> Result<Vec<IpAddr>, ResolveError> {
+    if let Ok(addr) = host.parse::<IpAddr>() {
+        return Ok(vec![addr]);
+    }
+
+    if let Some(entry) = CACHE.lock().unwrap().get(host) {
+        if entry.expires > Instant::now() {
+            return Ok(entry.addrs.clone());
+        }
+    }
+
+    let servers = dns_servers();
+    if servers.is_empty() {
+        return Err(ResolveError::NoServers);
+    }
+
+    let mut last_error = ResolveError::Timeout {
+        host: host.to_string(),
+    };
+    for server in servers {
+        match query_server(server, host).await {
+            Ok(records) if !records.is_empty() => {
+                let ttl = records
+                    .iter()
+                    .map(|r| r.ttl)
+                    .min()
+                    .unwrap_or(0)
+                    .clamp(MIN_TTL_SECS, MAX_TTL_SECS);
+                let addrs: Vec<IpAddr> = records.into_iter().map(|r| r.addr).collect();
+
+                info!("Resolved {host} to {addrs:?} (TTL {ttl}s)");
+                CACHE.lock().unwrap().insert(
+                    host.to_string(),
+                    CacheEntry {
+                        addrs: addrs.clone(),
+                        expires: Instant::now() + Duration::from_secs(ttl as u64),
+                    },
+                );
+                return Ok(addrs);
+            }
+            Ok(_) => {
+                last_error = ResolveError::Dns {
+                    host: host.to_string(),
+                    error: DnsError::NoRecords,
+                }
+            }
+            // The name definitely doesn't exist, another server won't change that
+            Err(ResolveError::Dns {
+                error: DnsError::NxDomain,
+                ..
+            }) => {
+                return Err(ResolveError::Dns {
+                    host: host.to_string(),
+                    error: DnsError::NxDomain,
+                })
+            }
+            Err(e) => {
+                warn!("DNS server {server} failed for {host}: {e}");
+                last_error = e;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Drops `host` from the cache, e.g. after none of its addresses accepted a connection.
+pub fn invalidate(host: &str) {
+    CACHE.lock().unwrap().remove(host);
+}
+
+/// Sends A and AAAA queries for `host` to `server` and collects the answers. Only
+/// fails if neither query gets one, so that a bad AAAA reply doesn't lose the IPv4
+/// addresses.
+async fn query_server(server: SocketAddr, host: &str) -> Result<Vec<Record>, ResolveError> {
+    let bind: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = Async::<UdpSocket>::bind(bind)?;
+    socket.get_ref().connect(server)?;
+
+    let id = (unsafe { sys::esp_random() } & 0xFFFF) as u16;
+    let ids = [id, id.wrapping_add(1)];
+    for (id, qtype) in ids.iter().zip([RecordType::Aaaa, RecordType::A]) {
+        let query = build_query(*id, host, qtype).map_err(|error| ResolveError::Dns {
+            host: host.to_string(),
+            error,
+        })?;
+        socket.send(&query).await?;
+    }
+
+    let deadline = Instant::now() + QUERY_TIMEOUT;
+    let mut records = Vec::new();
+    // Whether either query got a usable answer, even an empty one
+    let mut answered = false;
+    let mut error = None;
+    let mut pending = ids.to_vec();
+    let mut buf = [0u8; 512];
+
+    while !pending.is_empty() {
+        let Ok(received) = with_deadline(deadline, socket.recv(&mut buf)).await else {
+            break;
+        };
+        let n = received?;
+        if n < 2 {
+            continue;
+        }
+        // Stray or duplicate datagrams are ignored, like any other resolver does
+        let resp_id = u16::from_be_bytes([buf[0], buf[1]]);
+        let Some(idx) = pending.iter().position(|id| *id == resp_id) else {
+            continue;
+        };
+        pending.swap_remove(idx);
+
+        match parse_response(resp_id, &buf[..n]) {
+            Ok(mut answer) => {
+                answered = true;
+                records.append(&mut answer);
+            }
+            // The other query's answer may still be usable
+            Err(e) => error = Some(e),
+        }
+    }
+
+    if answered {
+        return Ok(records);
+    }
+    let host = host.to_string();
+    Err(match error {
+        Some(error) => ResolveError::Dns { host, error },
+        None => ResolveError::Timeout { host },
+    })
+}
+
+/// DNS servers handed out by DHCP, as recorded by lwIP.
+fn dns_servers() -> Vec<SocketAddr> {
+    // `ip_addr_t::type_` values from lwip/ip_addr.h
+    const IPADDR_TYPE_V4: u8 = 0;
+    const IPADDR_TYPE_V6: u8 = 6;
+
+    (0..MAX_DNS_SERVERS)
+        .filter_map(|i| {
+            let server = unsafe { sys::dns_getserver(i).as_ref()? };
+            let ip = match server.type_ {
+                IPADDR_TYPE_V4 => IpAddr::V4(Ipv4Addr::from(
+                    unsafe { server.u_addr.ip4.addr }.to_ne_bytes(),
+                )),
+                IPADDR_TYPE_V6 => {
+                    let words = unsafe { server.u_addr.ip6.addr };
+                    let mut octets = [0u8; 16];
+                    for (chunk, word) in octets.chunks_mut(4).zip(words) {
+                        chunk.copy_from_slice(&word.to_ne_bytes());
+                    }
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                }
+                _ => return None,
+            };
+            (!ip.is_unspecified()).then_some(SocketAddr::new(ip, 53))
+        })
+        .collect()
+}
@@ -0,0 +1,45 @@
+//! Queue of messages for the server that any task can add to. `ws_listen` sends
+//! them while connected; while it isn't, they wait here.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::{Channel, TrySendError};
+
+use crate::telemetry;
+
+use super::commands::{Event, EventMessage};
+use super::unix_now;
+
+/// Events beyond this many push out the oldest one.
+const CAPACITY: usize = 32;
+
+static OUTBOX: Channel<CriticalSectionRawMutex, EventMessage, CAPACITY> = Channel::new();
+
+/// Queues `event` for the server. Never blocks, so it's safe to call from the render
+/// loop; if the server has been unreachable long enough to fill the queue, the oldest
+/// event is dropped instead.
+pub fn publish(event: Event) {
+    if event.is_notable() {
+        telemetry::push_now();
+    }
+    let mut msg = EventMessage {
+        event,
+        at: unix_now(),
+    };
+    loop {
+        match OUTBOX.try_send(msg) {
+            Ok(()) => return,
+            Err(TrySendError::Full(rejected)) => {
+                msg = rejected;
+                if let Ok(dropped) = OUTBOX.try_receive() {
+                    log::warn!("Event queue full, dropped {:?}", dropped.event);
+                }
+            }
+        }
+    }
+}
+
+/// Waits for the next queued event. Cancel-safe: an event is only taken from the
+/// queue when this completes.
+pub(super) async fn next() -> EventMessage {
+    OUTBOX.receive().await
+}
@@ -1,245 +1,332 @@
+use core::fmt;
 use core::str::FromStr;
 
-use esp_idf_svc::tls::EspAsyncTls;
+use embassy_time::{Duration, Timer};
+use log::warn;
+use sign_proto::dns::DnsError;
+use sign_proto::http::{read_body, read_head, write_request, BodyTooLarge, DEFAULT_MAX_BODY_SIZE};
 use url::Url;
 
-use crate::{convert_error, EspTlsSocket};
+pub use sign_proto::http::HttpResponse;
+
+use super::auth::HttpSignature;
+use super::backoff::Backoff;
+use super::dns::ResolveError;
+use super::tls::TlsError;
+use super::{generate_tls, TlsConnection};
+
+/// How long each phase of a request may take before it is abandoned.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub handshake: Duration,
+    /// Applies to each individual read, not the whole response.
+    pub read: Duration,
+    /// Applies to each individual write, so a peer that stops reading can't stall
+    /// sending the request forever.
+    pub write: Duration,
+}
 
-use super::generate_tls;
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            handshake: Duration::from_secs(15),
+            read: Duration::from_secs(15),
+            write: Duration::from_secs(15),
+        }
+    }
+}
 
-pub struct HttpResponse {
-    pub status: u16,
-    pub headers: Vec<(String, String)>,
-    pub body: Vec<u8>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Connect,
+    Handshake,
+    Read,
+    Write,
 }
 
-impl HttpResponse {
-    pub fn header(&self, name: &str) -> Option<&str> {
-        let name_lower = name.to_lowercase();
-        self.headers
-            .iter()
-            .find(|(k, _)| k.to_lowercase() == name_lower)
-            .map(|(_, v)| v.as_str())
+#[derive(Debug)]
+pub struct TimeoutError(pub Phase);
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} timed out", self.0)
     }
 }
 
-fn build_request_head(method: &str, url: &Url, headers: &[(&str, &str)]) -> String {
-    let path = if let Some(q) = url.query() {
-        format!("{}?{}", url.path(), q)
-    } else {
-        url.path().to_string()
+impl std::error::Error for TimeoutError {}
+
+/// When and how often a failed request is repeated.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    pub backoff: Backoff,
+    /// Responses with these status codes are retried like transport errors.
+    pub retry_statuses: &'static [u16],
+}
+
+impl RetryPolicy {
+    pub const fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::DEFAULT
+        }
+    }
+
+    const DEFAULT: Self = Self {
+        max_attempts: 4,
+        backoff: Backoff::new(Duration::from_millis(500), Duration::from_secs(16)),
+        retry_statuses: &[408, 429, 500, 502, 503, 504],
     };
-    let host = url.host_str().unwrap_or("");
 
-    let mut req =
-        format!("{method} {path} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: PHSign/1.0.0\r\n");
-    for (k, v) in headers {
-        req.push_str(&format!("{k}: {v}\r\n"));
+    /// Repeating a request that isn't idempotent could carry it out twice, so only
+    /// those that are get retried unless the caller opts in with [`Request::retry`].
+    fn default_for(method: &str) -> Self {
+        match method {
+            "GET" | "HEAD" => Self::DEFAULT,
+            _ => Self::none(),
+        }
     }
-    req.push_str("Connection: close\r\n\r\n");
-    req
 }
 
-async fn read_response(tls: &mut EspAsyncTls<EspTlsSocket>) -> anyhow::Result<HttpResponse> {
-    // Read headers byte by byte until we hit \r\n\r\n
-    let mut header_buf = Vec::with_capacity(2048);
-    let mut b = [0u8; 1];
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
 
-    loop {
-        let n = tls.read(&mut b).await.map_err(convert_error)?;
-        if n == 0 {
-            anyhow::bail!("Connection closed before headers complete");
-        }
-        header_buf.push(b[0]);
+/// Longest server-requested `Retry-After` we are willing to sit through.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+const MAX_REDIRECTS: usize = 5;
+
+/// Builder for an outbound HTTPS request.
+pub struct Request<'a> {
+    method: &'static str,
+    url: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+    body: &'a [u8],
+    timeouts: Timeouts,
+    retry: RetryPolicy,
+    /// Largest response body [`Request::send`] will buffer.
+    max_body_size: usize,
+    /// Device key to sign the request with.
+    key: Option<&'a str>,
+}
 
-        if header_buf.len() >= 4 && header_buf[header_buf.len() - 4..] == *b"\r\n\r\n" {
-            break;
+impl<'a> Request<'a> {
+    pub fn new(method: &'static str, url: &'a str) -> Self {
+        Self {
+            method,
+            url,
+            headers: Vec::new(),
+            body: &[],
+            timeouts: Timeouts::default(),
+            retry: RetryPolicy::default_for(method),
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            key: None,
         }
+    }
 
-        if header_buf.len() > 16384 {
-            anyhow::bail!("Headers too large");
-        }
+    pub fn get(url: &'a str) -> Self {
+        Self::new("GET", url)
     }
 
-    let header_str = String::from_utf8(header_buf)?;
-    let mut lines = header_str.split("\r\n");
-
-    // Parse status line
-    let status_line = lines
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("Missing status line"))?;
-    let status = status_line
-        .split_whitespace()
-        .nth(1)
-        .ok_or_else(|| anyhow::anyhow!("Missing status code"))?
-        .parse::<u16>()?;
-
-    // Parse headers
-    let mut headers = Vec::new();
-    for line in lines {
-        if line.is_empty() {
-            break;
-        }
-        if let Some((k, v)) = line.split_once(": ") {
-            headers.push((k.to_string(), v.to_string()));
-        }
+    pub fn post(url: &'a str) -> Self {
+        Self::new("POST", url)
     }
 
-    // Read body based on Content-Length
-    let content_length: Option<usize> = headers
-        .iter()
-        .find(|(k, _)| k.to_lowercase() == "content-length")
-        .and_then(|(_, v)| v.parse().ok());
-
-    let body = if let Some(len) = content_length {
-        let mut body = vec![0u8; len];
-        let mut read_total = 0;
-        while read_total < len {
-            let n = tls
-                .read(&mut body[read_total..])
-                .await
-                .map_err(convert_error)?;
-            if n == 0 {
-                break;
-            }
-            read_total += n;
-        }
-        body.truncate(read_total);
-        body
-    } else {
-        // Read until EOF
-        let mut body = Vec::new();
-        let mut buf = [0u8; 4096];
-        loop {
-            let n = tls.read(&mut buf).await.map_err(convert_error)?;
-            if n == 0 {
-                break;
-            }
-            body.extend_from_slice(&buf[..n]);
-        }
-        body
-    };
+    pub fn header(mut self, name: &'a str, value: &'a str) -> Self {
+        self.headers.push((name, value));
+        self
+    }
 
-    Ok(HttpResponse {
-        status,
-        headers,
-        body,
-    })
-}
+    /// Makes the request conditional on the resource having changed since `etag`.
+    /// An unchanged resource comes back as `304 Not Modified` with an empty body.
+    pub fn if_none_match(self, etag: &'a str) -> Self {
+        self.header("If-None-Match", etag)
+    }
 
-pub async fn http_get(url: &str, headers: &[(&str, &str)]) -> anyhow::Result<HttpResponse> {
-    let parsed = Url::from_str(url)?;
-    let mut tls = generate_tls(url).await?;
+    /// Authenticates the request with the device key, as described in [`super::auth`].
+    /// Every attempt and redirect is signed afresh, except redirects to another origin,
+    /// which are sent unsigned.
+    pub fn signed(mut self, key: &'a str) -> Self {
+        self.key = Some(key);
+        self
+    }
 
-    let req = build_request_head("GET", &parsed, headers);
-    tls.write_all(req.as_bytes()).await.map_err(convert_error)?;
+    pub fn body(mut self, body: &'a [u8]) -> Self {
+        self.body = body;
+        self
+    }
 
-    read_response(&mut tls).await
-}
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
 
-pub async fn http_post(
-    url: &str,
-    headers: &[(&str, &str)],
-    body: &[u8],
-) -> anyhow::Result<HttpResponse> {
-    let parsed = Url::from_str(url)?;
-    let mut tls = generate_tls(url).await?;
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
 
-    let len_str = body.len().to_string();
-    let mut all_headers = headers.to_vec();
-    all_headers.push(("Content-Length", &len_str));
+    /// Replaces [`DEFAULT_MAX_BODY_SIZE`] as the largest body [`Request::send`] reads.
+    /// Streaming downloads aren't limited.
+    pub fn max_body_size(mut self, size: usize) -> Self {
+        self.max_body_size = size;
+        self
+    }
 
-    let req = build_request_head("POST", &parsed, &all_headers);
-    tls.write_all(req.as_bytes()).await.map_err(convert_error)?;
-    tls.write_all(body).await.map_err(convert_error)?;
+    /// Sends the request and reads the whole response body. Fails with
+    /// [`BodyTooLarge`] if it's longer than the limit.
+    pub async fn send(&self) -> anyhow::Result<HttpResponse> {
+        self.with_retries(move || async move {
+            let (mut conn, mut resp) = self.send_once().await?;
+            resp.body = read_body(&mut conn, &resp, self.max_body_size).await?;
+            Ok(resp)
+        })
+        .await
+    }
 
-    read_response(&mut tls).await
-}
+    /// Sends the request and returns the connection positioned at the start of the
+    /// body, for streaming downloads like OTA. The returned response has an empty body.
+    pub async fn send_streaming(&self) -> anyhow::Result<(TlsConnection, HttpResponse)> {
+        self.with_retries(move || self.send_once()).await
+    }
+
+    async fn with_retries<T, F, Fut>(&self, mut attempt_fn: F) -> anyhow::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: core::future::Future<Output = anyhow::Result<T>>,
+        T: AsStatus,
+    {
+        let mut attempt = 0;
+        loop {
+            let result = attempt_fn().await;
+            attempt += 1;
 
-/// Follows redirects for a GET request. Returns the final TLS stream and response
-/// (with headers parsed but body not yet fully consumed — caller can stream from tls).
-pub async fn follow_redirect(
-    url: &str,
-    headers: &[(&str, &str)],
-) -> anyhow::Result<(EspAsyncTls<EspTlsSocket>, HttpResponse)> {
-    let mut current_url = url.to_string();
+            let retry_after = match &result {
+                Ok(resp) if self.retry.retry_statuses.contains(&resp.status()) => {
+                    warn!("{} {} returned {}", self.method, self.url, resp.status());
+                    resp.retry_after()
+                }
+                Ok(_) => return result,
+                Err(e) if !is_retryable(e) => return result,
+                Err(e) => {
+                    warn!("{} {} failed: {e}", self.method, self.url);
+                    None
+                }
+            };
 
-    for _ in 0..5 {
-        let parsed = Url::from_str(&current_url)?;
-        let mut tls = generate_tls(&current_url).await?;
+            if attempt >= self.retry.max_attempts {
+                return result;
+            }
 
-        let req = build_request_head("GET", &parsed, headers);
-        tls.write_all(req.as_bytes()).await.map_err(convert_error)?;
+            let delay = retry_after
+                .map(|d| d.min(MAX_RETRY_AFTER))
+                .unwrap_or_else(|| self.retry.backoff.delay(attempt - 1));
+            warn!(
+                "Retrying in {}ms (attempt {}/{})",
+                delay.as_millis(),
+                attempt + 1,
+                self.retry.max_attempts
+            );
+            Timer::after(delay).await;
+        }
+    }
 
-        let resp = read_response(&mut tls).await?;
+    async fn send_once(&self) -> anyhow::Result<(TlsConnection, HttpResponse)> {
+        let mut url = Url::from_str(self.url)?;
+        let mut method = self.method;
+        let mut body = self.body;
+        let mut headers = self.headers.clone();
+        let origin = url.origin();
+
+        for _ in 0..MAX_REDIRECTS {
+            // Signatures are only for our own servers, wherever a redirect points
+            let signature = match self.key {
+                Some(key) if url.origin() == origin => {
+                    Some(HttpSignature::new(key, method, &url, body)?)
+                }
+                _ => None,
+            };
+            let mut request_headers = headers.clone();
+            if let Some(signature) = &signature {
+                request_headers.extend(signature.headers());
+            }
 
-        if (300..400).contains(&resp.status) {
-            if let Some(location) = resp.header("location") {
-                current_url = location.to_string();
-                continue;
+            let mut conn = generate_tls(url.as_str(), &self.timeouts).await?;
+
+            write_request(&mut conn, method, &url, &request_headers, body).await?;
+            let resp = read_head(&mut conn).await?;
+
+            if (300..400).contains(&resp.status) {
+                if let Some(location) = resp.header("location") {
+                    url = url.join(location)?;
+                    // Only 307 and 308 promise the same request works at the new URL;
+                    // for the rest clients fetch it with a plain GET instead
+                    if matches!(resp.status, 301..=303) && method != "HEAD" {
+                        method = "GET";
+                        body = &[];
+                        headers.retain(|(name, _)| !name.eq_ignore_ascii_case("content-type"));
+                    }
+                    continue;
+                }
             }
+
+            return Ok((conn, resp));
         }
 
-        return Ok((tls, resp));
+        anyhow::bail!("Too many redirects")
     }
-
-    anyhow::bail!("Too many redirects")
 }
 
-/// Like follow_redirect but returns the TLS stream positioned at the start of the body
-/// (headers already consumed). Used for streaming downloads like OTA.
-pub async fn follow_redirect_stream(
-    url: &str,
-    headers: &[(&str, &str)],
-) -> anyhow::Result<EspAsyncTls<EspTlsSocket>> {
-    let mut current_url = url.to_string();
+trait AsStatus {
+    fn status(&self) -> u16;
+    fn retry_after(&self) -> Option<Duration>;
+}
 
-    for _ in 0..5 {
-        let parsed = Url::from_str(&current_url)?;
-        let tls = generate_tls(&current_url).await?;
+impl AsStatus for HttpResponse {
+    fn status(&self) -> u16 {
+        self.status
+    }
 
-        let req = build_request_head("GET", &parsed, headers);
-        tls.write_all(req.as_bytes()).await.map_err(convert_error)?;
+    fn retry_after(&self) -> Option<Duration> {
+        self.retry_after_secs().map(Duration::from_secs)
+    }
+}
 
-        // Read headers only (byte by byte until \r\n\r\n)
-        let mut header_buf = Vec::with_capacity(2048);
-        let mut b = [0u8; 1];
-        loop {
-            let n = tls.read(&mut b).await.map_err(convert_error)?;
-            if n == 0 {
-                anyhow::bail!("Connection closed before headers complete");
-            }
-            header_buf.push(b[0]);
-            if header_buf.len() >= 4 && header_buf[header_buf.len() - 4..] == *b"\r\n\r\n" {
-                break;
-            }
-        }
+impl AsStatus for (TlsConnection, HttpResponse) {
+    fn status(&self) -> u16 {
+        self.1.status()
+    }
 
-        let header_str = String::from_utf8(header_buf)?;
-        let status: u16 = header_str
-            .split_whitespace()
-            .nth(1)
-            .unwrap_or("0")
-            .parse()
-            .unwrap_or(0);
-
-        if (300..400).contains(&status) {
-            // Find Location header
-            for line in header_str.split("\r\n") {
-                if let Some(loc) = line
-                    .strip_prefix("Location: ")
-                    .or_else(|| line.strip_prefix("location: "))
-                {
-                    current_url = loc.to_string();
-                    break;
-                }
-            }
-            continue;
-        }
+    fn retry_after(&self) -> Option<Duration> {
+        self.1.retry_after()
+    }
+}
 
-        // Body starts here — return the stream
-        return Ok(tls);
+/// Certificate problems, missing domains and oversized responses won't fix
+/// themselves, so don't hammer the server with them.
+fn is_retryable(e: &anyhow::Error) -> bool {
+    if e.is::<BodyTooLarge>() {
+        return false;
+    }
+    if let Some(ResolveError::Dns {
+        error: DnsError::NxDomain,
+        ..
+    }) = e.downcast_ref::<ResolveError>()
+    {
+        return false;
     }
 
-    anyhow::bail!("Too many redirects")
+    !matches!(
+        e.downcast_ref::<TlsError>(),
+        Some(
+            TlsError::CertificateRejected { .. }
+                | TlsError::FingerprintMismatch { .. }
+                | TlsError::InvalidCredentials(_)
+        )
+    )
 }
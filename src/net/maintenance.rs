@@ -0,0 +1,41 @@
+//! Commands that end with a restart. They're only carried out once the server has
+//! the reply, since the connection won't survive them.
+
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::hal::reset::restart;
+use log::{info, warn};
+
+use super::DeviceConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Maintenance {
+    Reboot,
+    /// Wipes the stored configuration, WiFi networks included.
+    FactoryReset {
+        keep_key: bool,
+    },
+    /// Restarts into safe mode for one boot.
+    SafeMode,
+}
+
+impl Maintenance {
+    pub fn perform(self, config: &Arc<Mutex<DeviceConfig>>) -> ! {
+        match self {
+            Self::Reboot => info!("Rebooting at the server's request"),
+            Self::FactoryReset { keep_key } => {
+                warn!("Factory reset requested (keep_key: {keep_key})");
+                if let Err(e) = config.lock().unwrap().factory_reset(keep_key) {
+                    log::error!("Factory reset failed: {e}");
+                }
+            }
+            Self::SafeMode => {
+                info!("Restarting into safe mode");
+                if let Err(e) = config.lock().unwrap().set_safe_mode(true) {
+                    log::error!("Failed to set safe mode: {e}");
+                }
+            }
+        }
+        restart();
+    }
+}
@@ -1,41 +1,187 @@
+pub mod auth;
+pub mod backoff;
 pub mod ble;
+pub mod commands;
 pub mod config;
+pub mod dns;
+pub mod events;
 pub mod http;
+pub mod maintenance;
+pub mod scan;
 pub mod self_update;
+pub mod tls;
 pub mod ws;
 
+use core::future::{poll_fn, Future};
+use core::pin::Pin;
 use core::str::FromStr;
-use std::net::TcpStream;
-use std::net::ToSocketAddrs;
+use core::task::Poll;
+use std::net::{IpAddr, SocketAddr, TcpStream};
 
 use async_io_mini::Async;
+use chrono::{Datelike, Utc};
 use dotenvy_macro::dotenv;
+use embassy_futures::select::{select, select4, Either, Either4};
+use embassy_time::{with_deadline, with_timeout, Duration, Instant, Timer};
 use esp_idf_svc::tls::EspAsyncTls;
 use esp_idf_svc::wifi::{AsyncWifi, ClientConfiguration, Configuration, EspWifi};
 use log::info;
+use sign_proto::dns::happy_eyeballs_order;
+use sign_proto::io::Connection;
 use url::Url;
 
-use crate::{anyesp, convert_error, EspTlsSocket};
+use crate::{anyesp, convert_error, display, logging, settings, telemetry, EspTlsSocket};
+use backoff::Backoff;
 
 pub use config::{DeviceConfig, WifiNetwork};
 pub use self_update::self_update;
 
-pub async fn generate_tls(url: &str) -> anyhow::Result<EspAsyncTls<EspTlsSocket>> {
+/// Current Unix time, or `None` if SNTP hasn't set the clock yet.
+pub fn unix_now() -> Option<i64> {
+    let now = Utc::now();
+    (now.year() >= 2024).then_some(now.timestamp())
+}
+
+/// TLS stream usable by the protocol code in `sign_proto`, optionally giving up on
+/// reads that take longer than `read_timeout` and writes that take longer than
+/// `write_timeout`.
+pub struct TlsConnection {
+    tls: EspAsyncTls<EspTlsSocket>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl TlsConnection {
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+}
+
+impl Connection for TlsConnection {
+    async fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        match self.read_timeout {
+            Some(timeout) => with_timeout(timeout, self.tls.read(buf))
+                .await
+                .map_err(|_| http::TimeoutError(http::Phase::Read))?
+                .map_err(convert_error),
+            None => self.tls.read(buf).await.map_err(convert_error),
+        }
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        match self.write_timeout {
+            Some(timeout) => with_timeout(timeout, self.tls.write_all(buf))
+                .await
+                .map_err(|_| http::TimeoutError(http::Phase::Write))?
+                .map_err(convert_error),
+            None => self.tls.write_all(buf).await.map_err(convert_error),
+        }
+    }
+}
+
+pub async fn generate_tls(url: &str, timeouts: &http::Timeouts) -> anyhow::Result<TlsConnection> {
     let url = Url::from_str(url)?;
     let host = url
         .host_str()
         .ok_or_else(|| anyhow::anyhow!("No host in URL"))?;
-    let addr = format!("{host}:443")
-        .to_socket_addrs()?
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("DNS resolution failed for {host}"))?;
+    let port = url.port().unwrap_or(443);
 
-    let socket = Async::<TcpStream>::connect(addr).await?;
+    let policy = tls::policy_for(host);
+
+    let addrs = dns::resolve(host).await?;
+    let socket = connect_any(host, port, &addrs, timeouts.connect).await?;
     let mut tls = EspAsyncTls::adopt(EspTlsSocket::new(socket))?;
-    tls.negotiate(host, &esp_idf_svc::tls::Config::new())
-        .await?;
+    with_timeout(timeouts.handshake, tls::negotiate(&mut tls, host, &policy))
+        .await
+        .map_err(|_| http::TimeoutError(http::Phase::Handshake))??;
+
+    Ok(TlsConnection {
+        tls,
+        read_timeout: Some(timeouts.read),
+        write_timeout: Some(timeouts.write),
+    })
+}
+
+/// How long an attempt gets on its own before the next address is tried alongside it,
+/// RFC 8305's "Connection Attempt Delay".
+const CONNECT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+type ConnectAttempt = Pin<Box<dyn Future<Output = std::io::Result<Async<TcpStream>>>>>;
+
+/// Connects to whichever of `addrs` accepts first. Attempts start in happy-eyeballs
+/// order, each [`CONNECT_ATTEMPT_DELAY`] after the previous one or as soon as it
+/// fails, and earlier ones keep going meanwhile. All of them share `timeout`.
+async fn connect_any(
+    host: &str,
+    port: u16,
+    addrs: &[IpAddr],
+    timeout: Duration,
+) -> anyhow::Result<Async<TcpStream>> {
+    let deadline = Instant::now() + timeout;
+    let mut untried = happy_eyeballs_order(addrs)
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port));
+    let mut attempts: Vec<(SocketAddr, ConnectAttempt)> = Vec::new();
+    // `None` once every address has an attempt
+    let mut next_attempt = Some(Instant::now());
 
-    Ok(tls)
+    let mut last_error = anyhow::anyhow!("No addresses for {host}");
+    loop {
+        if next_attempt.is_some_and(|at| Instant::now() >= at) {
+            match untried.next() {
+                Some(addr) => {
+                    attempts.push((addr, Box::pin(Async::<TcpStream>::connect(addr))));
+                    next_attempt = Some(Instant::now() + CONNECT_ATTEMPT_DELAY);
+                }
+                None => next_attempt = None,
+            }
+        }
+        if attempts.is_empty() && next_attempt.is_none() {
+            break;
+        }
+
+        let start_next = async move {
+            match next_attempt {
+                Some(at) => Timer::at(at).await,
+                None => core::future::pending().await,
+            }
+        };
+        let any_finished = poll_fn(|cx| {
+            for (i, (_, attempt)) in attempts.iter_mut().enumerate() {
+                if let Poll::Ready(result) = attempt.as_mut().poll(cx) {
+                    return Poll::Ready((i, result));
+                }
+            }
+            Poll::Pending
+        });
+        let outcome = with_deadline(deadline, select(any_finished, start_next)).await;
+
+        match outcome {
+            Err(_) => {
+                log::warn!("Connecting to {host} timed out");
+                last_error = http::TimeoutError(http::Phase::Connect).into();
+                break;
+            }
+            Ok(Either::First((i, result))) => {
+                let (addr, _) = attempts.swap_remove(i);
+                match result {
+                    Ok(socket) => return Ok(socket),
+                    Err(e) => {
+                        log::warn!("Connecting to {addr} failed: {e}");
+                        last_error = tls::TlsError::Connect(e).into();
+                        if next_attempt.is_some() {
+                            next_attempt = Some(Instant::now());
+                        }
+                    }
+                }
+            }
+            Ok(Either::Second(())) => {}
+        }
+    }
+
+    // The addresses may have moved, so look them up again next time
+    dns::invalidate(host);
+    Err(last_error)
 }
 
 enum NetworkSetupInfo {
@@ -217,6 +363,8 @@ pub async fn connect_to_network_with(
 }
 
 const WS_URL: &str = "wss://api.purduehackers.com/sign/ws";
+/// Subprotocol offered during the WebSocket handshake.
+const WS_PROTOCOL: &str = "ph-sign.v1";
 const PROVISION_URL: &str = "https://api.purduehackers.com/sign/provision";
 
 pub async fn provision_device(config: &mut DeviceConfig) -> anyhow::Result<()> {
@@ -232,12 +380,22 @@ pub async fn provision_device(config: &mut DeviceConfig) -> anyhow::Result<()> {
         return config.set_device_key(new_key);
     }
 
-    let resp = http::http_post(
-        PROVISION_URL,
-        &[("Content-Type", "application/json")],
-        b"{}",
-    )
-    .await?;
+    let key = request_device_key().await?;
+    config.set_device_key(&key)?;
+    info!("Device provisioned successfully");
+
+    Ok(())
+}
+
+/// Asks the server for a new device key.
+async fn request_device_key() -> anyhow::Result<String> {
+    // The server needs the ID to find the key when checking signatures
+    let body = serde_json::json!({ "device_id": auth::device_id() }).to_string();
+    let resp = http::Request::post(PROVISION_URL)
+        .header("Content-Type", "application/json")
+        .body(body.as_bytes())
+        .send()
+        .await?;
 
     if resp.status != 200 {
         anyhow::bail!("Provisioning failed with status {}", resp.status);
@@ -251,86 +409,548 @@ pub async fn provision_device(config: &mut DeviceConfig) -> anyhow::Result<()> {
     let body_str = core::str::from_utf8(&resp.body)?;
     let provision: ProvisionResponse = serde_json::from_str(body_str)?;
 
-    config.set_device_key(&provision.key)?;
-    info!("Device provisioned successfully");
+    Ok(provision.key)
+}
 
-    Ok(())
+/// Reconnect delays when nothing more specific applies.
+const WS_BACKOFF: Backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(300));
+/// A connection that lasts this long counts as stable and resets the backoff.
+const WS_STABLE_AFTER: Duration = Duration::from_secs(60);
+/// After a server restart, reconnects are spread over this long so the fleet doesn't
+/// arrive all at once.
+const WS_RESTART_SPREAD: Duration = Duration::from_secs(10);
+/// Upper bound on a delay requested by the server.
+const WS_MAX_RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+/// How long the server has to accept or refuse the device key.
+const WS_AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Minimum wait after the key is refused. A refusal is rarely temporary, but a
+/// server having a bad moment shouldn't cost the sign its key straight away.
+const WS_REJECTED_DELAY: Duration = Duration::from_secs(60);
+/// After the key is refused this many times in a row, it's thrown away and the sign
+/// provisions itself again.
+const WS_MAX_REJECTIONS: u32 = 3;
+
+/// How a WebSocket session ended.
+#[derive(Default)]
+struct SessionEnd {
+    /// The server's close frame, if it closed the connection with one.
+    close: Option<ws::CloseFrame>,
+    /// Delay the server asked for, in a `retry_after` message or the close reason.
+    retry_after: Option<Duration>,
+    error: Option<String>,
+    /// The server refused the device key.
+    rejected: bool,
+}
+
+impl SessionEnd {
+    fn error(error: impl ToString) -> Self {
+        Self {
+            error: Some(error.to_string()),
+            ..Default::default()
+        }
+    }
 }
 
-pub async fn ws_listen(key: String, config: std::sync::Arc<std::sync::Mutex<DeviceConfig>>) {
+/// Keeps a connection to the sign server, provisioning a new device key if there is
+/// none or the server keeps refusing it.
+pub async fn ws_listen(config: std::sync::Arc<std::sync::Mutex<DeviceConfig>>) {
+    crate::health::register_task("ws_listen");
+    let mut attempt = 0;
+    let mut rejections = 0;
+    // Taken from the event queue but not delivered before the connection dropped
+    let mut unsent = None;
     loop {
+        let key = config.lock().unwrap().get_device_key();
+        let Some(key) = key else {
+            ws::update_status(|s| s.auth = ws::AuthState::Reprovisioning);
+            match reprovision(&config).await {
+                Ok(()) => {
+                    ws::update_status(|s| s.auth = ws::AuthState::Pending);
+                    attempt = 0;
+                }
+                Err(e) => {
+                    log::error!("Provisioning failed: {e}");
+                    embassy_time::Timer::after(WS_BACKOFF.delay(attempt)).await;
+                    attempt = attempt.saturating_add(1);
+                }
+            }
+            continue;
+        };
+
         info!("Connecting to WebSocket...");
-        match ws::WebSocket::connect(WS_URL).await {
+        let options = ws::HandshakeOptions {
+            protocols: vec![WS_PROTOCOL.to_string()],
+            // Context takeover costs nothing extra, the inflater's window is allocated
+            // either way
+            deflate: Some(ws::DeflateConfig::default()),
+            ..Default::default()
+        };
+        let connected_at = Instant::now();
+        let end = match ws::connect(WS_URL, &options).await {
             Ok(mut ws_conn) => {
-                let auth = serde_json::json!({ "type": "auth", "key": key }).to_string();
-                if let Err(e) = ws_conn.send(&ws::WsMessage::Text(auth)).await {
-                    log::error!("WebSocket auth failed: {e}");
-                } else {
-                    info!("WebSocket authenticated");
-                    loop {
-                        match ws_conn.recv().await {
-                            Ok(ws::WsMessage::Text(text)) => {
-                                if let Err(e) =
-                                    handle_ws_command(&text, &mut ws_conn, &config).await
-                                {
-                                    log::error!("Error handling WS command: {e}");
-                                }
-                            }
-                            Ok(ws::WsMessage::Close) => {
-                                info!("WebSocket closed by server");
-                                break;
-                            }
-                            Ok(_) => {}
-                            Err(e) => {
-                                log::error!("WebSocket error: {e}");
-                                break;
-                            }
-                        }
-                    }
+                if ws_conn.protocol().is_none() {
+                    log::warn!("Server did not select {WS_PROTOCOL}, assuming it anyway");
+                }
+                ws::update_status(|s| s.connected = true);
+                let end = ws_session(&mut ws_conn, &key, &config, &mut unsent).await;
+                // A log stream only lasts as long as the connection that asked for it
+                logging::stream(logging::LogLevel::Off);
+                if connected_at.elapsed() >= WS_STABLE_AFTER {
+                    attempt = 0;
                 }
+                end
             }
             Err(e) => {
                 log::error!("WebSocket connection failed: {e}");
+                SessionEnd::error(e)
+            }
+        };
+
+        if end.rejected {
+            rejections += 1;
+            ws::update_status(|s| s.auth = ws::AuthState::Rejected(rejections));
+            if rejections >= WS_MAX_REJECTIONS {
+                log::warn!("Device key refused {rejections} times, provisioning a new one");
+                if let Err(e) = config.lock().unwrap().clear_device_key() {
+                    log::error!("Failed to clear device key: {e}");
+                }
+                rejections = 0;
+                attempt = 0;
+                continue;
+            }
+        } else if ws::auth_state() == ws::AuthState::Accepted {
+            rejections = 0;
+        }
+
+        let delay = reconnect_delay(&end, attempt);
+        attempt = attempt.saturating_add(1);
+        ws::update_status(|s| {
+            s.connected = false;
+            s.reconnects = s.reconnects.saturating_add(1);
+            s.consecutive_failures = attempt;
+            if end.error.is_some() {
+                s.last_error = end.error;
+            }
+            if end.close.is_some() {
+                s.last_close = end.close;
+            }
+        });
+
+        info!("Reconnecting WebSocket in {}ms...", delay.as_millis());
+        embassy_time::Timer::after(delay).await;
+    }
+}
+
+/// Replaces a device key the server no longer accepts.
+async fn reprovision(
+    config: &std::sync::Arc<std::sync::Mutex<DeviceConfig>>,
+) -> anyhow::Result<()> {
+    info!("Requesting a new device key...");
+    // The build-time key is the one that was refused, so always ask the server
+    let key = request_device_key().await?;
+    config.lock().unwrap().set_device_key(&key)?;
+    info!("Device re-provisioned successfully");
+    Ok(())
+}
+
+/// Authenticates, then handles commands and sends queued events until the connection
+/// ends. An event that couldn't be sent is left in `unsent`, and sent first next time.
+async fn ws_session(
+    ws_conn: &mut ws::WebSocket,
+    key: &str,
+    config: &std::sync::Arc<std::sync::Mutex<DeviceConfig>>,
+    unsent: &mut Option<commands::EventMessage>,
+) -> SessionEnd {
+    let challenge: commands::Challenge = match recv_during_auth(ws_conn).await {
+        Ok(challenge) => challenge,
+        Err(end) => return end,
+    };
+    let auth = auth::sign_challenge(key, &challenge.nonce)
+        .and_then(|signed| Ok(serde_json::to_string(&commands::Auth::new(&signed))?));
+    let auth = match auth {
+        Ok(auth) => auth,
+        Err(e) => {
+            log::error!("Failed to answer auth challenge: {e}");
+            return SessionEnd::error(e);
+        }
+    };
+    if let Err(e) = ws_conn.send(&ws::WsMessage::Text(auth)).await {
+        log::error!("WebSocket auth failed: {e}");
+        return SessionEnd::error(e);
+    }
+
+    let server_version = match recv_during_auth(ws_conn).await {
+        Ok(commands::AuthResult::AuthOk { protocol_version }) => protocol_version,
+        Ok(commands::AuthResult::AuthError { message }) => {
+            log::error!("Server refused device key: {message}");
+            let close = ws::CloseFrame::new(ws::close_code::NORMAL, "");
+            return SessionEnd {
+                close: ws::close_gracefully(ws_conn, close).await,
+                error: Some(format!("Device key refused: {message}")),
+                rejected: true,
+                ..Default::default()
+            };
+        }
+        Err(end) => return end,
+    };
+    info!("WebSocket authenticated (server protocol v{server_version})");
+    if server_version != commands::PROTOCOL_VERSION {
+        log::warn!(
+            "Server speaks protocol v{server_version}, this firmware v{}",
+            commands::PROTOCOL_VERSION
+        );
+    }
+    ws::update_status(|s| s.auth = ws::AuthState::Accepted);
+
+    let mut retry_after = None;
+    let mut heartbeat = ws::Heartbeat::new(ws::HeartbeatConfig::default());
+    let mut last_telemetry = Instant::now();
+    loop {
+        // `recv` only queues its pongs, since it may be cancelled by the other arms
+        // of the select below, so they're written here where nothing can interrupt
+        if let Err(e) = ws_conn.flush().await {
+            log::error!("WebSocket write failed: {e}");
+            return SessionEnd::error(e);
+        }
+        if let Some(event) = unsent.take() {
+            if let Err(e) = send_event(ws_conn, &event).await {
+                log::error!("Failed to send event: {e}");
+                *unsent = Some(event);
+                return SessionEnd::error(e);
             }
         }
 
-        info!("Reconnecting WebSocket in 5s...");
-        embassy_time::Timer::after_millis(5000).await;
+        let received = select4(
+            with_deadline(heartbeat.deadline(), ws_conn.recv()),
+            events::next(),
+            logging::next_streamed(),
+            telemetry::due(last_telemetry),
+        )
+        .await;
+        let msg = match received {
+            Either4::First(Ok(msg)) => msg,
+            Either4::Second(event) => {
+                *unsent = Some(event);
+                continue;
+            }
+            Either4::Third(record) => {
+                // Unlike events, streamed records aren't worth keeping for next time
+                let text = serde_json::to_string(&commands::LogMessage { record: &record });
+                let sent = match text {
+                    Ok(text) => ws_conn.send(&ws::WsMessage::Text(text)).await,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = sent {
+                    log::error!("Failed to stream log record: {e}");
+                    return SessionEnd::error(e);
+                }
+                continue;
+            }
+            Either4::Fourth(()) => {
+                last_telemetry = Instant::now();
+                let text = serde_json::to_string(&commands::TelemetryMessage {
+                    telemetry: telemetry::take(),
+                    at: unix_now(),
+                });
+                let sent = match text {
+                    Ok(text) => ws_conn.send(&ws::WsMessage::Text(text)).await,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = sent {
+                    log::error!("Failed to send telemetry: {e}");
+                    return SessionEnd::error(e);
+                }
+                continue;
+            }
+            Either4::First(Err(_)) => {
+                if let Err(e) = heartbeat.tick(ws_conn).await {
+                    // The link is dead, so there's no point in a closing handshake
+                    log::error!("WebSocket heartbeat failed: {e}");
+                    return SessionEnd::error(e);
+                }
+                continue;
+            }
+        };
+        if let Ok(msg) = &msg {
+            heartbeat.on_message(msg);
+        }
+
+        match msg {
+            Ok(ws::WsMessage::Text(text)) => {
+                if let Some(delay) = retry_after_hint(&text) {
+                    info!("Server asked to retry after {}s", delay.as_secs());
+                    retry_after = Some(delay);
+                    continue;
+                }
+                if let Err(e) = handle_ws_command(&text, ws_conn, config).await {
+                    log::error!("Error handling WS command: {e}");
+                }
+            }
+            Ok(ws::WsMessage::Close(close)) => {
+                match &close {
+                    Some(close) => info!("WebSocket closed by server: {close}"),
+                    None => info!("WebSocket closed by server"),
+                }
+                // Best effort, the connection is over either way
+                let _ = ws_conn.flush().await;
+                let reason_delay = close
+                    .as_ref()
+                    .and_then(|c| ws::retry_after_from_reason(&c.reason));
+                return SessionEnd {
+                    retry_after: reason_delay.or(retry_after),
+                    close,
+                    ..Default::default()
+                };
+            }
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("WebSocket error: {e}");
+                // `recv` has already queued the close frame for a protocol error, so
+                // this only sends it and gives the server a moment to answer. Anything
+                // else means the connection itself is broken.
+                let close = match e.downcast_ref::<ws::ProtocolError>() {
+                    Some(error) => {
+                        let close = ws::CloseFrame::new(error.close_code(), "");
+                        ws::close_gracefully(ws_conn, close).await
+                    }
+                    None => None,
+                };
+                return SessionEnd {
+                    close,
+                    retry_after,
+                    error: Some(e.to_string()),
+                    ..Default::default()
+                };
+            }
+        }
     }
 }
 
+/// Waits for the first message of type `T` while authenticating, ignoring others,
+/// or returns how the session ended instead.
+async fn recv_during_auth<T: serde::de::DeserializeOwned>(
+    ws_conn: &mut ws::WebSocket,
+) -> Result<T, SessionEnd> {
+    let deadline = Instant::now() + WS_AUTH_TIMEOUT;
+    let mut retry_after = None;
+    loop {
+        let msg = match with_deadline(deadline, ws_conn.recv()).await {
+            Ok(Ok(msg)) => msg,
+            Ok(Err(e)) => {
+                log::error!("WebSocket error during auth: {e}");
+                // Sends the close frame `recv` queued for a protocol error, if any
+                let _ = ws_conn.flush().await;
+                return Err(SessionEnd::error(e));
+            }
+            Err(_) => {
+                log::error!("Server did not finish auth in time");
+                return Err(SessionEnd::error("Timed out during auth"));
+            }
+        };
+
+        // A close is handled below even if its echo can't be sent
+        if !matches!(msg, ws::WsMessage::Close(_)) {
+            if let Err(e) = ws_conn.flush().await {
+                log::error!("WebSocket write failed during auth: {e}");
+                return Err(SessionEnd::error(e));
+            }
+        }
+
+        match msg {
+            ws::WsMessage::Text(text) => {
+                if let Some(delay) = retry_after_hint(&text) {
+                    retry_after = Some(delay);
+                    continue;
+                }
+                match serde_json::from_str(&text) {
+                    Ok(msg) => return Ok(msg),
+                    Err(_) => log::warn!("Ignoring unexpected message during auth"),
+                }
+            }
+            ws::WsMessage::Close(close) => {
+                let _ = ws_conn.flush().await;
+                // Servers that predate auth results refuse a key by closing the
+                // connection with a policy violation
+                let rejected = close
+                    .as_ref()
+                    .is_some_and(|c| c.code == ws::close_code::POLICY_VIOLATION);
+                let reason_delay = close
+                    .as_ref()
+                    .and_then(|c| ws::retry_after_from_reason(&c.reason));
+                return Err(SessionEnd {
+                    retry_after: reason_delay.or(retry_after),
+                    close,
+                    rejected,
+                    ..Default::default()
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn send_event(
+    ws_conn: &mut ws::WebSocket,
+    event: &commands::EventMessage,
+) -> anyhow::Result<()> {
+    let text = serde_json::to_string(event)?;
+    ws_conn.send(&ws::WsMessage::Text(text)).await
+}
+
+/// Delay from a `{"type": "retry_after", "seconds": N}` message, which the server
+/// sends ahead of closing the connection.
+fn retry_after_hint(text: &str) -> Option<Duration> {
+    let msg: serde_json::Value = serde_json::from_str(text).ok()?;
+    if msg["type"] != "retry_after" {
+        return None;
+    }
+    msg["seconds"].as_u64().map(Duration::from_secs)
+}
+
+/// How long to wait before reconnection attempt number `attempt` (starting at 0),
+/// taking the server's wishes into account.
+fn reconnect_delay(end: &SessionEnd, attempt: u32) -> Duration {
+    let backoff = WS_BACKOFF.delay(attempt);
+
+    if let Some(requested) = end.retry_after {
+        // Every sign gets the same request, so spread them over a quarter of it
+        let requested = requested.min(WS_MAX_RETRY_AFTER);
+        return requested + backoff::jitter(requested / 4);
+    }
+    if end.rejected {
+        return WS_REJECTED_DELAY + backoff;
+    }
+
+    match end.close.as_ref().map(|c| c.code) {
+        // The server will be back shortly, but so will everyone else
+        Some(ws::close_code::SERVICE_RESTART) => backoff.max(backoff::jitter(WS_RESTART_SPREAD)),
+        Some(ws::close_code::TRY_AGAIN_LATER) => Duration::from_secs(30) + backoff,
+        // Reconnecting straight away would just be rejected again
+        Some(ws::close_code::POLICY_VIOLATION) => Duration::from_secs(300) + backoff,
+        _ => backoff,
+    }
+}
+
+/// Runs a command from the server and sends the reply.
 async fn handle_ws_command(
     text: &str,
     ws_conn: &mut ws::WebSocket,
     config: &std::sync::Arc<std::sync::Mutex<DeviceConfig>>,
 ) -> anyhow::Result<()> {
-    let msg: serde_json::Value = serde_json::from_str(text)?;
-    let msg_type = msg["type"].as_str().unwrap_or("");
-    let request_id = msg["request_id"].as_str().unwrap_or("");
+    let (reply, maintenance) = match commands::parse_request(text) {
+        Ok(request) => {
+            let maintenance = request.command.maintenance();
+            let response = run_command(request.command, config)
+                .await
+                .unwrap_or_else(commands::Response::from);
+            (
+                commands::Reply::new(request.request_id, response),
+                maintenance,
+            )
+        }
+        Err(reply) => (reply, None),
+    };
+    if let commands::Response::Error { code, message } = &reply.response {
+        log::warn!("WS command failed ({code:?}): {message}");
+    }
+
+    let text = serde_json::to_string(&reply)?;
+    ws_conn.send(&ws::WsMessage::Text(text)).await?;
 
-    match msg_type {
-        "get_wifi" => {
+    if let Some(maintenance) = maintenance {
+        let close = ws::CloseFrame::new(ws::close_code::GOING_AWAY, "restarting");
+        ws::close_gracefully(ws_conn, close).await;
+        maintenance.perform(config);
+    }
+    Ok(())
+}
+
+async fn run_command(
+    command: commands::Command,
+    config: &std::sync::Arc<std::sync::Mutex<DeviceConfig>>,
+) -> Result<commands::Response, commands::CommandError> {
+    use commands::{Command, Response};
+
+    match command {
+        Command::GetWifi => {
             let networks = config.lock().unwrap().get_wifi_networks();
-            let resp = serde_json::json!({
-                "type": "wifi_networks",
-                "request_id": request_id,
-                "networks": networks,
-            });
-            ws_conn.send(&ws::WsMessage::Text(resp.to_string())).await?;
+            Ok(Response::WifiNetworks { networks })
         }
-        "set_wifi" => {
-            let networks: Vec<WifiNetwork> = serde_json::from_value(msg["networks"].clone())?;
+        Command::SetWifi { networks } => {
             config.lock().unwrap().set_wifi_networks(&networks)?;
-            let resp = serde_json::json!({
-                "type": "wifi_ack",
-                "request_id": request_id,
+            Ok(Response::Ack)
+        }
+        Command::GetStatus => Ok(Response::Status(Box::new(crate::health::device_status()))),
+        Command::SetColors {
+            color,
+            blocks,
+            fade_ms,
+            ttl_secs,
+        } => {
+            let mut colors = [color.map(|c| c.0); 5];
+            for (block, color) in blocks {
+                colors[block as usize] = Some(color.0);
+            }
+            display::set_override(display::Override {
+                colors,
+                fade: Duration::from_millis(fade_ms.into()),
+                ttl: ttl_secs.map(|secs| Duration::from_secs(secs.into())),
             });
-            ws_conn.send(&ws::WsMessage::Text(resp.to_string())).await?;
+            Ok(Response::Ack)
         }
-        other => {
-            info!("Unknown WS command: {other}");
+        Command::ClearColors => {
+            display::clear_override();
+            Ok(Response::Ack)
         }
+        Command::TriggerUpdate {
+            version,
+            url,
+            sha256,
+        } => {
+            let target = match (version, url, sha256) {
+                (Some(tag), _, _) => self_update::UpdateTarget::Tag(tag),
+                (_, Some(url), Some(sha256)) => self_update::UpdateTarget::Url { url, sha256 },
+                _ => self_update::UpdateTarget::Latest,
+            };
+            self_update::request_update(target);
+            Ok(Response::Ack)
+        }
+        Command::GetLogs => Ok(Response::Logs {
+            level: logging::level(),
+            records: logging::recent(),
+        }),
+        Command::StreamLogs { level } => {
+            logging::stream(level);
+            Ok(Response::Ack)
+        }
+        Command::SetLogLevel { level } => {
+            logging::set_level(level);
+            Ok(Response::Ack)
+        }
+        Command::ScanWifi => {
+            scan::request_scan();
+            Ok(Response::Ack)
+        }
+        Command::SetTelemetryInterval { seconds } => {
+            let changes = [(
+                settings::TELEMETRY_INTERVAL.to_string(),
+                settings::Value::Int(seconds.into()),
+            )];
+            settings::set(config, changes.into())?;
+            Ok(Response::Ack)
+        }
+        Command::GetConfig => Ok(Response::Config {
+            settings: settings::all(),
+        }),
+        Command::SetConfig { settings: changes } => {
+            settings::set(config, changes)?;
+            Ok(Response::Ack)
+        }
+        Command::Identify { seconds } => {
+            display::identify(Duration::from_secs(seconds.into()));
+            Ok(Response::Ack)
+        }
+        // Carried out by `handle_ws_command` once the reply is sent
+        Command::Reboot | Command::FactoryReset { .. } | Command::SafeMode => Ok(Response::Ack),
     }
-
-    Ok(())
 }
@@ -0,0 +1,102 @@
+//! WiFi scans for diagnosing connection trouble. The WiFi driver belongs to the
+//! render loop, so the WebSocket task asks it to scan instead of scanning itself, and
+//! the results come back as an event.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::wifi::{AsyncWifi, AuthMethod, EspWifi};
+use serde::Serialize;
+
+use crate::convert_error;
+
+use super::commands::Event;
+use super::{events, DeviceConfig, WifiNetwork};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessPoint {
+    pub ssid: String,
+    pub bssid: String,
+    pub channel: u8,
+    pub rssi: i8,
+    pub auth: &'static str,
+    /// One of the networks in [`DeviceConfig::get_wifi_networks`].
+    pub saved: bool,
+}
+
+/// Scans for access points, strongest first.
+pub async fn scan(
+    wifi: &mut AsyncWifi<EspWifi<'static>>,
+    saved: &[WifiNetwork],
+) -> anyhow::Result<Vec<AccessPoint>> {
+    // After a failed connection attempt the driver is stopped, and can't scan
+    let was_started = wifi.is_started().map_err(convert_error)?;
+    if !was_started {
+        wifi.start().await.map_err(convert_error)?;
+    }
+    let result = wifi.scan().await.map_err(convert_error);
+    if !was_started {
+        wifi.stop().await.map_err(convert_error)?;
+    }
+
+    let mut access_points: Vec<AccessPoint> = result?
+        .into_iter()
+        .map(|ap| AccessPoint {
+            saved: saved.iter().any(|network| network.ssid == ap.ssid.as_str()),
+            ssid: ap.ssid.to_string(),
+            bssid: ap
+                .bssid
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<Vec<_>>()
+                .join(":"),
+            channel: ap.channel,
+            rssi: ap.signal_strength,
+            auth: auth_name(ap.auth_method),
+        })
+        .collect();
+    access_points.sort_by_key(|ap| core::cmp::Reverse(ap.rssi));
+    Ok(access_points)
+}
+
+/// Has the render loop scan soon. Requests made before it gets to it share one scan.
+pub fn request_scan() {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Runs a scan if [`request_scan`] asked for one, and publishes the result. Called by
+/// the render loop.
+pub async fn serve_requested_scan(
+    wifi: &mut AsyncWifi<EspWifi<'static>>,
+    config: &Arc<Mutex<DeviceConfig>>,
+) {
+    if !REQUESTED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let saved = config.lock().unwrap().get_wifi_networks();
+    match scan(wifi, &saved).await {
+        Ok(access_points) => events::publish(Event::WifiScan { access_points }),
+        Err(e) => {
+            log::warn!("Requested WiFi scan failed: {e}");
+            events::publish(Event::WifiScanFailed {
+                error: e.to_string(),
+            });
+        }
+    }
+}
+
+fn auth_name(method: Option<AuthMethod>) -> &'static str {
+    match method {
+        None | Some(AuthMethod::None) => "open",
+        Some(AuthMethod::WEP) => "wep",
+        Some(AuthMethod::WPA) => "wpa",
+        Some(AuthMethod::WPA2Personal) => "wpa2",
+        Some(AuthMethod::WPAWPA2Personal) => "wpa_wpa2",
+        Some(AuthMethod::WPA2Enterprise) => "wpa2_enterprise",
+        Some(AuthMethod::WPA3Personal) => "wpa3",
+        Some(AuthMethod::WPA2WPA3Personal) => "wpa2_wpa3",
+        Some(_) => "other",
+    }
+}
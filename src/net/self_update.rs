@@ -1,103 +1,395 @@
 use core::str::FromStr;
+use std::sync::{Arc, Mutex};
 
-use embassy_time::with_timeout;
+use embassy_time::{with_timeout, Duration, Timer};
 use esp_idf_svc::hal::reset::restart;
 use esp_idf_svc::io::Write;
 use esp_idf_svc::ota::EspOta;
-use log::info;
+use log::{debug, info};
 use palette::rgb::Rgb;
+use sign_proto::io::Connection;
 
+use crate::display::{self, Mode};
 use crate::Leds;
 
-use super::http;
+use super::auth::Sha256;
+use super::commands::Event;
+use super::{events, http, unix_now, DeviceConfig};
 
 const IS_INTERACTIVE: bool = cfg!(feature = "interactive");
 
-#[derive(Debug, serde::Deserialize)]
-struct GithubResponse {
+/// GitHub API endpoint for this repository's releases.
+const RELEASES_URL: &str = "https://api.github.com/repos/purduehackers/sign-firmware/releases";
+
+/// How long to stay away from GitHub after a rate limit response that doesn't say.
+const DEFAULT_RATE_LIMIT_BACKOFF_SECS: i64 = 60 * 60;
+/// Without a `Content-Length`, progress is reported this often.
+const PROGRESS_STEP_BYTES: u64 = 128 * 1024;
+/// How long to wait after installing before restarting, so the server hears of it.
+const INSTALLED_GRACE: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct GithubResponse {
     tag_name: String,
     assets: Vec<GithubAsset>,
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct GithubAsset {
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct GithubAsset {
     browser_download_url: String,
     name: String,
 }
 
-pub async fn self_update(leds: &mut Leds) -> anyhow::Result<()> {
-    leds.set_all_colors(Rgb::new(0, 0, 255));
+/// Result of the last release check, persisted in NVS so that unchanged releases
+/// can be confirmed with a conditional request.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct ReleaseCache {
+    pub etag: Option<String>,
+    pub release: Option<GithubResponse>,
+    /// Unix time before which GitHub asked us not to come back.
+    pub retry_not_before: Option<i64>,
+}
 
-    info!("Checking for self-update");
+/// Outcome of the most recent release check, for status reports.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct UpdateCheck {
+    /// Unix time of the check, or `None` if the clock wasn't set.
+    pub at: Option<i64>,
+    /// Newest release seen, if the check got that far.
+    pub latest_version: Option<String>,
+    pub error: Option<String>,
+}
 
-    let resp = http::http_get(
-        "https://api.github.com/repos/purduehackers/sign-firmware/releases/latest",
-        &[],
-    )
-    .await?;
+static LAST_CHECK: Mutex<Option<UpdateCheck>> = Mutex::new(None);
 
-    let body_str = core::str::from_utf8(&resp.body)?;
-    let manifest: GithubResponse =
-        serde_json::from_str(body_str.trim().trim_end_matches(char::from(0)))?;
+pub fn last_check() -> Option<UpdateCheck> {
+    LAST_CHECK.lock().unwrap().clone()
+}
 
-    let local = semver::Version::new(
-        env!("CARGO_PKG_VERSION_MAJOR").parse()?,
-        env!("CARGO_PKG_VERSION_MINOR").parse()?,
-        env!("CARGO_PKG_VERSION_PATCH").parse()?,
-    );
+async fn fetch_latest_release(config: &Arc<Mutex<DeviceConfig>>) -> anyhow::Result<GithubResponse> {
+    let mut cache = config
+        .lock()
+        .unwrap()
+        .get_release_cache()
+        .unwrap_or_default();
 
-    let remote = semver::Version::from_str(&manifest.tag_name[1..])?;
+    if let (Some(not_before), Some(now)) = (cache.retry_not_before, unix_now()) {
+        if now < not_before {
+            anyhow::bail!(
+                "GitHub rate limit in effect for another {}s",
+                not_before - now
+            );
+        }
+    }
 
-    if remote > local {
-        info!("New release found! Downloading and updating");
-        leds.set_all_colors(Rgb::new(0, 255, 0));
+    // Rate limits last far longer than any retry backoff, so only retry server errors
+    let retry = http::RetryPolicy {
+        retry_statuses: &[500, 502, 503, 504],
+        ..Default::default()
+    };
 
-        let asset_name = if IS_INTERACTIVE {
-            "sign-firmware.bin"
-        } else {
-            "sign-firmware-passive.bin"
-        };
+    let etag = cache.etag.clone();
+    let mut request = http::Request::get(&latest_release_url()).retry(retry);
+    if let (Some(etag), Some(_)) = (&etag, &cache.release) {
+        request = request.if_none_match(etag);
+    }
+    let resp = request.send().await?;
 
-        let url = manifest
-            .assets
-            .into_iter()
-            .find(|asset| asset.name == asset_name)
-            .ok_or_else(|| anyhow::anyhow!("Release missing asset {asset_name}"))?
-            .browser_download_url;
-
-        let tls = http::follow_redirect_stream(&url, &[]).await?;
-
-        let mut body = [0u8; 8192];
-        let mut ota = EspOta::new()?;
-        let mut update = ota.initiate_update()?;
-
-        let mut chunk = 0_usize;
-        loop {
-            let read =
-                with_timeout(embassy_time::Duration::from_secs(10), tls.read(&mut body)).await;
-
-            match read {
-                Ok(Ok(read)) => {
-                    info!("[CHUNK {chunk:>4}] Read {read:>4}");
-                    update.write_all(&body[..read])?;
-                    if read == 0 {
-                        break;
-                    }
-                    chunk += 1;
-                }
-                Ok(Err(e)) => e.panic(),
-                Err(_) => break,
+    match resp.status {
+        200 => {
+            let body_str = core::str::from_utf8(&resp.body)?;
+            let manifest: GithubResponse =
+                serde_json::from_str(body_str.trim().trim_end_matches(char::from(0)))?;
+
+            cache = ReleaseCache {
+                etag: resp.etag().map(str::to_string),
+                release: Some(manifest.clone()),
+                retry_not_before: None,
             };
+            if let Err(e) = config.lock().unwrap().set_release_cache(&cache) {
+                log::warn!("Failed to cache release: {e}");
+            }
+
+            Ok(manifest)
+        }
+        304 => {
+            info!("Release unchanged since last check");
+            if cache.retry_not_before.take().is_some() {
+                config.lock().unwrap().set_release_cache(&cache)?;
+            }
+            cache
+                .release
+                .ok_or_else(|| anyhow::anyhow!("304 without a cached release"))
         }
+        403 | 429 => {
+            let now = unix_now();
+            let not_before = resp
+                .retry_after_secs()
+                .and_then(|secs| now.map(|now| now + secs as i64))
+                .or_else(|| resp.rate_limit_reset())
+                .or_else(|| now.map(|now| now + DEFAULT_RATE_LIMIT_BACKOFF_SECS));
+
+            cache.retry_not_before = not_before;
+            config.lock().unwrap().set_release_cache(&cache)?;
+
+            anyhow::bail!("GitHub rate limited the release check ({})", resp.status)
+        }
+        status => anyhow::bail!("Release check failed with status {status}"),
+    }
+}
+
+/// Which firmware to install.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateTarget {
+    /// The latest release, if it's newer than this firmware.
+    Latest,
+    /// The release with this tag, even if it's older.
+    Tag(String),
+    /// The image at this URL, whatever it is, as long as its SHA-256 matches.
+    Url { url: String, sha256: String },
+}
+
+/// Update asked for by the server, waiting for the render loop to pick it up.
+static REQUESTED: Mutex<Option<UpdateTarget>> = Mutex::new(None);
+
+/// Asks the render loop to run [`update`], since it owns the LEDs. Replaces any
+/// request it hasn't picked up yet.
+pub fn request_update(target: UpdateTarget) {
+    *REQUESTED.lock().unwrap() = Some(target);
+}
+
+pub fn take_requested_update() -> Option<UpdateTarget> {
+    REQUESTED.lock().unwrap().take()
+}
+
+/// Installs the latest release and restarts if it's newer than this firmware.
+pub async fn self_update(leds: &mut Leds, config: &Arc<Mutex<DeviceConfig>>) -> anyhow::Result<()> {
+    update(leds, config, UpdateTarget::Latest).await
+}
 
-        info!("Update completed! Activating...");
+/// Installs `target` and restarts, reporting progress to the server as events.
+/// Only returns if there was nothing to install or the update failed.
+pub async fn update(
+    leds: &mut Leds,
+    config: &Arc<Mutex<DeviceConfig>>,
+    target: UpdateTarget,
+) -> anyhow::Result<()> {
+    display::set_mode(Mode::Updating);
+    leds.set_all_colors(Rgb::new(0, 0, 255));
 
-        update.finish()?.activate()?;
+    let result = match resolve(config, &target).await {
+        Ok(Plan::UpToDate(version)) => {
+            info!("Already on latest version.");
+            events::publish(Event::UpToDate {
+                version: version.to_string(),
+            });
+            Ok(Some(version.to_string()))
+        }
+        Ok(Plan::Install {
+            version,
+            url,
+            sha256,
+        }) => {
+            leds.set_all_colors(Rgb::new(0, 255, 0));
+            events::publish(Event::UpdateStarted {
+                version: version.clone(),
+            });
+            match install(&url, &version, sha256.as_deref()).await {
+                Ok(()) => {
+                    events::publish(Event::UpdateInstalled { version });
+                    // Give the WebSocket a moment to deliver the news
+                    Timer::after(INSTALLED_GRACE).await;
+                    restart();
+                }
+                Err(e) => {
+                    events::publish(Event::UpdateFailed {
+                        version,
+                        error: e.to_string(),
+                    });
+                    Err(e)
+                }
+            }
+        }
+        Err(e) => {
+            events::publish(Event::UpdateFailed {
+                version: None,
+                error: e.to_string(),
+            });
+            Err(e)
+        }
+    };
+
+    *LAST_CHECK.lock().unwrap() = Some(UpdateCheck {
+        at: unix_now(),
+        latest_version: result.as_ref().ok().cloned().flatten(),
+        error: result.as_ref().err().map(ToString::to_string),
+    });
+    result.map(|_| ())
+}
 
-        restart();
+enum Plan {
+    UpToDate(semver::Version),
+    Install {
+        /// `None` when installing straight from a URL.
+        version: Option<String>,
+        url: String,
+        /// Hex SHA-256 the image must have, for images that aren't from a release.
+        sha256: Option<String>,
+    },
+}
+
+async fn resolve(config: &Arc<Mutex<DeviceConfig>>, target: &UpdateTarget) -> anyhow::Result<Plan> {
+    match target {
+        UpdateTarget::Latest => {
+            info!("Checking for self-update");
+            let manifest = fetch_latest_release(config).await?;
+
+            let local = semver::Version::new(
+                env!("CARGO_PKG_VERSION_MAJOR").parse()?,
+                env!("CARGO_PKG_VERSION_MINOR").parse()?,
+                env!("CARGO_PKG_VERSION_PATCH").parse()?,
+            );
+            let remote = semver::Version::from_str(manifest.tag_name.trim_start_matches('v'))?;
+            if remote <= local {
+                return Ok(Plan::UpToDate(remote));
+            }
+
+            info!("New release found! Downloading and updating");
+            Ok(Plan::Install {
+                version: Some(remote.to_string()),
+                url: asset_url(manifest)?,
+                sha256: None,
+            })
+        }
+        UpdateTarget::Tag(tag) => {
+            info!("Installing release {tag}");
+            let manifest = fetch_release(tag).await?;
+            Ok(Plan::Install {
+                version: Some(manifest.tag_name.trim_start_matches('v').to_string()),
+                url: asset_url(manifest)?,
+                sha256: None,
+            })
+        }
+        UpdateTarget::Url { url, sha256 } => {
+            info!("Installing firmware from {url}");
+            Ok(Plan::Install {
+                version: None,
+                url: url.clone(),
+                sha256: Some(sha256.clone()),
+            })
+        }
+    }
+}
+
+fn latest_release_url() -> String {
+    format!("{RELEASES_URL}/latest")
+}
+
+fn release_url(tag: &str) -> String {
+    format!("{RELEASES_URL}/tags/{tag}")
+}
+
+async fn fetch_release(tag: &str) -> anyhow::Result<GithubResponse> {
+    let resp = http::Request::get(&release_url(tag)).send().await?;
+    match resp.status {
+        200 => {
+            let body_str = core::str::from_utf8(&resp.body)?;
+            let manifest: GithubResponse =
+                serde_json::from_str(body_str.trim().trim_end_matches(char::from(0)))?;
+            // Anything else means the lookup went to the wrong endpoint
+            if manifest.tag_name != tag {
+                anyhow::bail!("Asked for release {tag} but got {}", manifest.tag_name);
+            }
+            Ok(manifest)
+        }
+        404 => anyhow::bail!("No release tagged {tag}"),
+        status => anyhow::bail!("Release lookup failed with status {status}"),
+    }
+}
+
+fn asset_url(manifest: GithubResponse) -> anyhow::Result<String> {
+    let asset_name = if IS_INTERACTIVE {
+        "sign-firmware.bin"
     } else {
-        info!("Already on latest version.");
+        "sign-firmware-passive.bin"
+    };
+
+    Ok(manifest
+        .assets
+        .into_iter()
+        .find(|asset| asset.name == asset_name)
+        .ok_or_else(|| anyhow::anyhow!("Release missing asset {asset_name}"))?
+        .browser_download_url)
+}
+
+/// Downloads the firmware image at `url` into the next OTA slot and activates it,
+/// unless it doesn't match the hex SHA-256 `expected`.
+async fn install(
+    url: &str,
+    version: &Option<String>,
+    expected: Option<&str>,
+) -> anyhow::Result<()> {
+    let (mut conn, resp) = http::Request::get(url).send_streaming().await?;
+    if resp.status != 200 {
+        anyhow::bail!("Firmware download failed with status {}", resp.status);
     }
+    let total = resp
+        .header("content-length")
+        .and_then(|len| len.trim().parse::<u64>().ok());
+
+    let mut body = [0u8; 8192];
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    let mut hasher = expected.map(|_| Sha256::new()).transpose()?;
+
+    let mut chunk = 0_usize;
+    let mut written = 0_u64;
+    let mut reported = 0_u64;
+    loop {
+        let read = with_timeout(Duration::from_secs(10), conn.read(&mut body)).await;
+
+        match read {
+            Ok(Ok(read)) => {
+                debug!("[CHUNK {chunk:>4}] Read {read:>4}");
+                update.write_all(&body[..read])?;
+                if let Some(hasher) = &mut hasher {
+                    hasher.update(&body[..read])?;
+                }
+                if read == 0 {
+                    break;
+                }
+                chunk += 1;
+
+                written += read as u64;
+                let step = total.map_or(PROGRESS_STEP_BYTES, |total| total / 10);
+                if written - reported >= step.max(1) {
+                    reported = written;
+                    events::publish(Event::UpdateProgress {
+                        version: version.clone(),
+                        bytes: written,
+                        total,
+                    });
+                }
+            }
+            Ok(Err(e)) => return Err(e),
+            // Dropping `update` without finishing it discards the partial image
+            Err(_) => anyhow::bail!("Firmware download stalled after {written} bytes"),
+        };
+    }
+
+    if let Some(total) = total.filter(|&total| total != written) {
+        anyhow::bail!("Firmware download ended after {written} of {total} bytes");
+    }
+    if let (Some(hasher), Some(expected)) = (hasher, expected) {
+        let actual = hasher.finish()?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            // Dropping `update` without finishing it discards the image
+            anyhow::bail!("Firmware SHA-256 is {actual}, expected {expected}");
+        }
+    }
+
+    info!("Update completed! Activating...");
 
+    update.finish()?.activate()?;
     Ok(())
 }
@@ -0,0 +1,259 @@
+use core::fmt;
+use std::ffi::CString;
+
+use esp_idf_svc::sys::{self, EspError};
+use esp_idf_svc::tls::{Config, EspAsyncTls, X509};
+
+use crate::EspTlsSocket;
+
+const API_HOST: &str = "api.purduehackers.com";
+/// Parsed while building, so that a malformed `API_CERT_SHA256` fails the build
+/// instead of every connection.
+const API_CERT_FINGERPRINT: Option<[u8; 32]> = match option_env!("API_CERT_SHA256") {
+    Some(hex) => Some(parse_fingerprint(hex)),
+    None => None,
+};
+
+/// Which certificates a server is allowed to present.
+#[derive(Debug, Clone, Copy)]
+pub enum TrustAnchor {
+    /// Any certificate chaining to the ESP-IDF CA bundle.
+    CaBundle,
+    /// Only certificates chaining to this PEM-encoded CA.
+    PinnedCa(&'static str),
+}
+
+/// PEM-encoded certificate and private key presented for mutual TLS.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIdentity {
+    pub cert: &'static str,
+    pub key: &'static str,
+}
+
+/// TLS settings for a single endpoint.
+#[derive(Debug, Clone, Copy)]
+pub struct TlsPolicy {
+    pub trust: TrustAnchor,
+    /// SHA-256 of the DER-encoded leaf certificate, checked after the handshake.
+    pub leaf_fingerprint: Option<[u8; 32]>,
+    pub client_identity: Option<ClientIdentity>,
+}
+
+impl TlsPolicy {
+    pub const fn ca_bundle() -> Self {
+        Self {
+            trust: TrustAnchor::CaBundle,
+            leaf_fingerprint: None,
+            client_identity: None,
+        }
+    }
+}
+
+/// Returns the policy used for connections to `host`.
+///
+/// Pins and client credentials are baked in at build time from `API_CA_CERT`,
+/// `API_CERT_SHA256`, `SIGN_CLIENT_CERT` and `SIGN_CLIENT_KEY`. Hosts without
+/// any of these fall back to the CA bundle.
+pub fn policy_for(host: &str) -> TlsPolicy {
+    if host != API_HOST {
+        return TlsPolicy::ca_bundle();
+    }
+
+    let trust = match option_env!("API_CA_CERT") {
+        Some(pem) => TrustAnchor::PinnedCa(pem),
+        None => TrustAnchor::CaBundle,
+    };
+
+    let client_identity = match (
+        option_env!("SIGN_CLIENT_CERT"),
+        option_env!("SIGN_CLIENT_KEY"),
+    ) {
+        (Some(cert), Some(key)) => Some(ClientIdentity { cert, key }),
+        _ => None,
+    };
+
+    TlsPolicy {
+        trust,
+        leaf_fingerprint: API_CERT_FINGERPRINT,
+        client_identity,
+    }
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    /// The TCP connection could not be opened.
+    Connect(std::io::Error),
+    /// The policy holds a certificate or key that could not be loaded.
+    InvalidCredentials(&'static str),
+    /// The server certificate failed verification against the trust anchor.
+    CertificateRejected { host: String, flags: i32 },
+    /// The handshake failed for a reason other than certificate verification.
+    Handshake { host: String, error: EspError },
+    /// The server did not present a certificate to check the pin against.
+    MissingPeerCertificate { host: String },
+    /// The leaf certificate does not match the pinned fingerprint.
+    FingerprintMismatch { host: String, actual: [u8; 32] },
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connect(e) => write!(f, "TCP connect failed: {e}"),
+            Self::InvalidCredentials(what) => write!(f, "Invalid TLS credentials: {what}"),
+            Self::CertificateRejected { host, flags } => {
+                write!(f, "Certificate for {host} rejected (flags 0x{flags:x})")
+            }
+            Self::Handshake { host, error } => {
+                write!(f, "TLS handshake with {host} failed: {error}")
+            }
+            Self::MissingPeerCertificate { host } => {
+                write!(f, "{host} did not present a certificate")
+            }
+            Self::FingerprintMismatch { host, actual } => {
+                write!(f, "Certificate pin mismatch for {host}, got ")?;
+                for b in actual {
+                    write!(f, "{b:02x}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+/// Runs the TLS handshake on `tls` according to `policy`.
+pub async fn negotiate(
+    tls: &mut EspAsyncTls<EspTlsSocket>,
+    host: &str,
+    policy: &TlsPolicy,
+) -> Result<(), TlsError> {
+    // Certificates have to outlive the handshake, and ESP-TLS wants them NUL-terminated
+    let ca_cert = match policy.trust {
+        TrustAnchor::PinnedCa(pem) => Some(pem_cstring(pem, "CA certificate")?),
+        TrustAnchor::CaBundle => None,
+    };
+    let client = policy
+        .client_identity
+        .map(|id| {
+            Ok::<_, TlsError>((
+                pem_cstring(id.cert, "client certificate")?,
+                pem_cstring(id.key, "client key")?,
+            ))
+        })
+        .transpose()?;
+
+    let mut config = Config::new();
+    match &ca_cert {
+        Some(pem) => config.ca_cert = Some(X509::pem(pem)),
+        None => config.use_crt_bundle_attach = true,
+    }
+    if let Some((cert, key)) = &client {
+        config.client_cert = Some(X509::pem(cert));
+        config.client_key = Some(X509::pem(key));
+    }
+
+    if let Err(error) = tls.negotiate(host, &config).await {
+        return Err(match last_verify_flags(tls) {
+            Some(flags) if flags != 0 => TlsError::CertificateRejected {
+                host: host.to_string(),
+                flags,
+            },
+            _ => TlsError::Handshake {
+                host: host.to_string(),
+                error,
+            },
+        });
+    }
+
+    if let Some(expected) = policy.leaf_fingerprint {
+        let actual = leaf_fingerprint(tls).ok_or_else(|| TlsError::MissingPeerCertificate {
+            host: host.to_string(),
+        })?;
+        if actual != expected {
+            return Err(TlsError::FingerprintMismatch {
+                host: host.to_string(),
+                actual,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn pem_cstring(pem: &str, what: &'static str) -> Result<CString, TlsError> {
+    CString::new(pem).map_err(|_| TlsError::InvalidCredentials(what))
+}
+
+fn last_verify_flags(tls: &EspAsyncTls<EspTlsSocket>) -> Option<i32> {
+    let mut handle: sys::esp_tls_error_handle_t = core::ptr::null_mut();
+    let mut code = 0;
+    let mut flags = 0;
+
+    unsafe {
+        if sys::esp_tls_get_error_handle(tls.context_handle(), &mut handle) != sys::ESP_OK
+            || handle.is_null()
+        {
+            return None;
+        }
+        sys::esp_tls_get_and_clear_last_error(handle, &mut code, &mut flags);
+    }
+
+    Some(flags)
+}
+
+fn leaf_fingerprint(tls: &EspAsyncTls<EspTlsSocket>) -> Option<[u8; 32]> {
+    let mut digest = [0u8; 32];
+
+    unsafe {
+        let ssl =
+            sys::esp_tls_get_ssl_context(tls.context_handle()) as *const sys::mbedtls_ssl_context;
+        if ssl.is_null() {
+            return None;
+        }
+        let cert = sys::mbedtls_ssl_get_peer_cert(ssl);
+        if cert.is_null() {
+            return None;
+        }
+        let raw = &(*cert).raw;
+        if sys::mbedtls_sha256(raw.p, raw.len as _, digest.as_mut_ptr(), 0) != 0 {
+            return None;
+        }
+    }
+
+    Some(digest)
+}
+
+/// Parses 64 hex digits, optionally with a `:` between each byte as `openssl x509
+/// -fingerprint` prints them. Anything else is rejected rather than skipped, so a typo
+/// can't quietly pin a different certificate.
+const fn parse_fingerprint(text: &str) -> [u8; 32] {
+    let text = text.as_bytes();
+    let mut out = [0u8; 32];
+    let mut pos = 0;
+    let mut i = 0;
+    while i < out.len() {
+        if i > 0 && pos < text.len() && text[pos] == b':' {
+            pos += 1;
+        }
+        if pos + 2 > text.len() {
+            panic!("API_CERT_SHA256 is shorter than 32 bytes of hex");
+        }
+        out[i] = (hex_digit(text[pos]) << 4) | hex_digit(text[pos + 1]);
+        pos += 2;
+        i += 1;
+    }
+    if pos != text.len() {
+        panic!("API_CERT_SHA256 is longer than 32 bytes of hex");
+    }
+    out
+}
+
+const fn hex_digit(c: u8) -> u8 {
+    match c {
+        b'0'..=b'9' => c - b'0',
+        b'a'..=b'f' => c - b'a' + 10,
+        b'A'..=b'F' => c - b'A' + 10,
+        _ => panic!("API_CERT_SHA256 may only contain hex digits and ':' between bytes"),
+    }
+}
@@ -1,245 +1,267 @@
+use core::fmt;
 use core::str::FromStr;
+use core::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
 
-use esp_idf_svc::tls::EspAsyncTls;
+use embassy_time::{with_timeout, Duration, Instant};
 use url::Url;
 
-use crate::{convert_error, EspTlsSocket};
+pub use sign_proto::deflate::DeflateConfig;
+pub use sign_proto::ws::{close_code, CloseFrame, HandshakeOptions, ProtocolError, WsMessage};
+
+use super::http::Timeouts;
+use super::{generate_tls, TlsConnection};
+
+/// Outgoing messages are split into fragments of this size so that large replies
+/// don't need one huge TLS write.
+const MAX_FRAGMENT_SIZE: usize = 4096;
+/// How long to wait for the server to answer our close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type WebSocket = sign_proto::ws::WebSocket<TlsConnection>;
+
+/// State of the server connection, for status reports.
+#[derive(Debug, Clone)]
+pub struct ConnectionStatus {
+    pub connected: bool,
+    /// Times `ws_listen` has had to reconnect since boot.
+    pub reconnects: u32,
+    /// Failed attempts and short-lived connections since the last stable one.
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    /// Close frame the server last ended a connection with.
+    pub last_close: Option<CloseFrame>,
+    pub auth: AuthState,
+}
 
-use super::generate_tls;
+/// Whether the server accepts our device key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthState {
+    /// Not answered yet since boot or since the key was replaced.
+    Pending,
+    Accepted,
+    /// Refused this many times in a row.
+    Rejected(u32),
+    /// Given up on the old key and waiting for a new one.
+    Reprovisioning,
+}
 
-#[derive(Debug)]
-pub enum WsMessage {
-    Text(String),
-    Binary(Vec<u8>),
-    Ping(Vec<u8>),
-    Pong(Vec<u8>),
-    Close,
+static STATUS: Mutex<ConnectionStatus> = Mutex::new(ConnectionStatus {
+    connected: false,
+    reconnects: 0,
+    consecutive_failures: 0,
+    last_error: None,
+    last_close: None,
+    auth: AuthState::Pending,
+});
+
+pub fn status() -> ConnectionStatus {
+    STATUS.lock().unwrap().clone()
 }
 
-pub struct WebSocket {
-    tls: EspAsyncTls<EspTlsSocket>,
+/// Just the auth part of [`status`], cheap enough for the render loop.
+pub fn auth_state() -> AuthState {
+    STATUS.lock().unwrap().auth
 }
 
-impl WebSocket {
-    pub async fn connect(url: &str) -> anyhow::Result<Self> {
-        let parsed = Url::from_str(url)?;
-        let host = parsed
-            .host_str()
-            .ok_or_else(|| anyhow::anyhow!("No host in URL"))?;
-        let path = if let Some(q) = parsed.query() {
-            format!("{}?{}", parsed.path(), q)
-        } else {
-            parsed.path().to_string()
-        };
+pub(super) fn update_status(f: impl FnOnce(&mut ConnectionStatus)) {
+    f(&mut STATUS.lock().unwrap());
+}
 
-        // Generate random 16-byte key using ESP hardware RNG
-        let mut key_bytes = [0u8; 16];
-        unsafe {
-            esp_idf_svc::sys::esp_fill_random(key_bytes.as_mut_ptr() as *mut core::ffi::c_void, 16);
-        }
-        let ws_key = base64_encode(&key_bytes);
-
-        // Use wss:// -> connect via TLS
-        let tls_url = url
-            .replace("ws://", "http://")
-            .replace("wss://", "https://");
-        let tls = generate_tls(&tls_url).await?;
-
-        // Send WebSocket upgrade request
-        let req = format!(
-            "GET {path} HTTP/1.1\r\n\
-             Host: {host}\r\n\
-             Upgrade: websocket\r\n\
-             Connection: Upgrade\r\n\
-             Sec-WebSocket-Key: {ws_key}\r\n\
-             Sec-WebSocket-Version: 13\r\n\
-             User-Agent: PHSign/1.0.0\r\n\
-             \r\n"
-        );
+/// Delay requested in a close reason such as `"deploying; retry-after=30"`.
+pub fn retry_after_from_reason(reason: &str) -> Option<Duration> {
+    reason
+        .split(|c: char| c == ';' || c == ',' || c.is_whitespace())
+        .find_map(|part| {
+            let (key, value) = part.split_once('=')?;
+            matches!(key, "retry-after" | "retry_after").then_some(value)
+        })?
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
 
-        tls.write_all(req.as_bytes()).await.map_err(convert_error)?;
+/// Round-trip time of the most recent ping in milliseconds, or `u32::MAX` before the
+/// first pong.
+static LAST_RTT_MS: AtomicU32 = AtomicU32::new(u32::MAX);
 
-        // Read response headers
-        let mut header_buf = Vec::with_capacity(1024);
-        let mut b = [0u8; 1];
-        loop {
-            let n = tls.read(&mut b).await.map_err(convert_error)?;
-            if n == 0 {
-                anyhow::bail!("Connection closed during WebSocket handshake");
-            }
-            header_buf.push(b[0]);
-            if header_buf.len() >= 4 && header_buf[header_buf.len() - 4..] == *b"\r\n\r\n" {
-                break;
-            }
-        }
+/// Round-trip time measured by the most recent answered ping.
+pub fn last_rtt() -> Option<Duration> {
+    match LAST_RTT_MS.load(Ordering::Relaxed) {
+        u32::MAX => None,
+        ms => Some(Duration::from_millis(ms as u64)),
+    }
+}
 
-        let header_str = String::from_utf8(header_buf)?;
-        let status: u16 = header_str
-            .split_whitespace()
-            .nth(1)
-            .unwrap_or("0")
-            .parse()
-            .unwrap_or(0);
+/// Client-side keepalive. A half-open TCP link never makes `recv` fail on its own,
+/// so without this a dead connection can go unnoticed for hours.
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    /// Send a ping after this long without one.
+    pub ping_interval: Duration,
+    /// Give up on the connection if a ping isn't answered within this long.
+    pub pong_timeout: Duration,
+    /// Give up on the connection if nothing at all arrives for this long.
+    pub idle_timeout: Duration,
+}
 
-        if status != 101 {
-            anyhow::bail!("WebSocket handshake failed with status {status}");
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(20),
+            pong_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(60),
         }
-
-        Ok(WebSocket { tls })
     }
+}
 
-    pub async fn send(&mut self, msg: &WsMessage) -> anyhow::Result<()> {
-        let (opcode, payload) = match msg {
-            WsMessage::Text(s) => (0x01, s.as_bytes().to_vec()),
-            WsMessage::Binary(b) => (0x02, b.clone()),
-            WsMessage::Ping(b) => (0x09, b.clone()),
-            WsMessage::Pong(b) => (0x0A, b.clone()),
-            WsMessage::Close => (0x08, Vec::new()),
-        };
-
-        let frame = encode_frame(opcode, &payload)?;
-        self.tls.write_all(&frame).await.map_err(convert_error)?;
-
-        Ok(())
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeartbeatError {
+    /// A ping went unanswered.
+    PongTimeout,
+    /// Nothing was received within the idle timeout.
+    Idle,
+}
 
-    pub async fn recv(&mut self) -> anyhow::Result<WsMessage> {
-        loop {
-            let msg = self.read_frame().await?;
-            match &msg {
-                WsMessage::Ping(data) => {
-                    let pong = WsMessage::Pong(data.clone());
-                    self.send(&pong).await?;
-                    continue;
-                }
-                _ => return Ok(msg),
-            }
+impl fmt::Display for HeartbeatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PongTimeout => write!(f, "No pong received before the deadline"),
+            Self::Idle => write!(f, "Connection idle for too long"),
         }
     }
+}
+
+impl std::error::Error for HeartbeatError {}
+
+/// Tracks pings and incoming traffic for one connection. Wait on
+/// [`Heartbeat::deadline`] alongside `recv`, pass every received message to
+/// [`Heartbeat::on_message`], and call [`Heartbeat::tick`] when the deadline passes.
+pub struct Heartbeat {
+    config: HeartbeatConfig,
+    last_rx: Instant,
+    next_ping: Instant,
+    /// Sequence number and send time of the unanswered ping, if any.
+    outstanding: Option<(u32, Instant)>,
+    seq: u32,
+}
 
-    async fn read_frame(&mut self) -> anyhow::Result<WsMessage> {
-        let mut header = [0u8; 2];
-        read_exact(&mut self.tls, &mut header).await?;
-
-        let opcode = header[0] & 0x0F;
-        let masked = header[1] & 0x80 != 0;
-        let mut payload_len = (header[1] & 0x7F) as u64;
-
-        if payload_len == 126 {
-            let mut buf = [0u8; 2];
-            read_exact(&mut self.tls, &mut buf).await?;
-            payload_len = u16::from_be_bytes(buf) as u64;
-        } else if payload_len == 127 {
-            let mut buf = [0u8; 8];
-            read_exact(&mut self.tls, &mut buf).await?;
-            payload_len = u64::from_be_bytes(buf);
+impl Heartbeat {
+    pub fn new(config: HeartbeatConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            config,
+            last_rx: now,
+            next_ping: now + config.ping_interval,
+            outstanding: None,
+            seq: 0,
         }
+    }
 
-        let mask_key = if masked {
-            let mut buf = [0u8; 4];
-            read_exact(&mut self.tls, &mut buf).await?;
-            Some(buf)
-        } else {
-            None
+    /// When [`Heartbeat::tick`] next needs to run.
+    pub fn deadline(&self) -> Instant {
+        let idle = self.last_rx + self.config.idle_timeout;
+        let next = match self.outstanding {
+            Some((_, sent)) => sent + self.config.pong_timeout,
+            None => self.next_ping,
         };
+        next.min(idle)
+    }
 
-        let mut payload = vec![0u8; payload_len as usize];
-        read_exact(&mut self.tls, &mut payload).await?;
-
-        if let Some(mask) = mask_key {
-            for (i, byte) in payload.iter_mut().enumerate() {
-                *byte ^= mask[i % 4];
-            }
-        }
+    pub fn on_message(&mut self, msg: &WsMessage) {
+        let now = Instant::now();
+        self.last_rx = now;
 
-        match opcode {
-            0x01 => Ok(WsMessage::Text(String::from_utf8(payload)?)),
-            0x02 => Ok(WsMessage::Binary(payload)),
-            0x08 => Ok(WsMessage::Close),
-            0x09 => Ok(WsMessage::Ping(payload)),
-            0x0A => Ok(WsMessage::Pong(payload)),
-            _ => anyhow::bail!("Unknown WebSocket opcode: {opcode}"),
+        let WsMessage::Pong(payload) = msg else {
+            return;
+        };
+        let Some((seq, sent)) = self.outstanding else {
+            return;
+        };
+        // Unsolicited pongs or answers to older pings don't count
+        if payload.as_slice() != seq.to_be_bytes() {
+            return;
         }
-    }
 
-    pub async fn close(&mut self) -> anyhow::Result<()> {
-        self.send(&WsMessage::Close).await
+        let rtt = now - sent;
+        LAST_RTT_MS.store(
+            rtt.as_millis().min(u32::MAX as u64 - 1) as u32,
+            Ordering::Relaxed,
+        );
+        log::debug!("WebSocket RTT {}ms", rtt.as_millis());
+        self.outstanding = None;
     }
-}
 
-async fn read_exact(tls: &mut EspAsyncTls<EspTlsSocket>, buf: &mut [u8]) -> anyhow::Result<()> {
-    let mut offset = 0;
-    while offset < buf.len() {
-        let n = tls.read(&mut buf[offset..]).await.map_err(convert_error)?;
-        if n == 0 {
-            anyhow::bail!("Connection closed unexpectedly");
+    /// Sends a ping if one is due, or fails if the connection looks dead.
+    pub async fn tick(&mut self, ws: &mut WebSocket) -> anyhow::Result<()> {
+        let now = Instant::now();
+        if now >= self.last_rx + self.config.idle_timeout {
+            return Err(HeartbeatError::Idle.into());
+        }
+        if let Some((_, sent)) = self.outstanding {
+            if now >= sent + self.config.pong_timeout {
+                return Err(HeartbeatError::PongTimeout.into());
+            }
+            return Ok(());
+        }
+        if now < self.next_ping {
+            return Ok(());
         }
-        offset += n;
-    }
-    Ok(())
-}
-
-fn encode_frame(opcode: u8, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
-    let mut frame = Vec::new();
-
-    // FIN bit + opcode
-    frame.push(0x80 | opcode);
 
-    // Mask bit (client must mask) + payload length
-    let len = payload.len();
-    if len < 126 {
-        frame.push(0x80 | len as u8);
-    } else if len <= 65535 {
-        frame.push(0x80 | 126);
-        frame.extend_from_slice(&(len as u16).to_be_bytes());
-    } else {
-        frame.push(0x80 | 127);
-        frame.extend_from_slice(&(len as u64).to_be_bytes());
-    }
+        self.seq = self.seq.wrapping_add(1);
+        let ping = WsMessage::Ping(self.seq.to_be_bytes().to_vec());
+        // A send into a full socket buffer is the other way a dead link shows up
+        with_timeout(self.config.pong_timeout, ws.send(&ping))
+            .await
+            .map_err(|_| HeartbeatError::PongTimeout)??;
 
-    // Generate random mask
-    let mut mask = [0u8; 4];
-    unsafe {
-        esp_idf_svc::sys::esp_fill_random(mask.as_mut_ptr() as *mut core::ffi::c_void, 4);
+        self.outstanding = Some((self.seq, now));
+        self.next_ping = now + self.config.ping_interval;
+        Ok(())
     }
-    frame.extend_from_slice(&mask);
+}
 
-    // Masked payload
-    for (i, &byte) in payload.iter().enumerate() {
-        frame.push(byte ^ mask[i % 4]);
+/// Opens a TLS connection to `url` and upgrades it to a WebSocket.
+pub async fn connect(url: &str, options: &HandshakeOptions) -> anyhow::Result<WebSocket> {
+    let parsed = Url::from_str(url)?;
+
+    // Use wss:// -> connect via TLS
+    let tls_url = url
+        .replace("ws://", "http://")
+        .replace("wss://", "https://");
+    let conn = generate_tls(&tls_url, &Timeouts::default()).await?;
+
+    let mut ws = WebSocket::handshake(conn, &parsed, options, fill_random).await?;
+    // The handshake is bounded by the read timeout, but an idle socket is normal after it
+    ws.connection_mut().set_read_timeout(None);
+    ws.set_max_fragment_size(Some(MAX_FRAGMENT_SIZE));
+    if ws.is_compressed() {
+        log::info!("WebSocket compression enabled");
     }
 
-    Ok(frame)
+    Ok(ws)
 }
 
-// Minimal base64 encode — only needs to handle 16 bytes for WebSocket key
-const BASE64_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
-
-fn base64_encode(input: &[u8]) -> String {
-    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
-
-    for chunk in input.chunks(3) {
-        let b0 = chunk[0] as u32;
-        let b1 = if chunk.len() > 1 { chunk[1] as u32 } else { 0 };
-        let b2 = if chunk.len() > 2 { chunk[2] as u32 } else { 0 };
-
-        let triple = (b0 << 16) | (b1 << 8) | b2;
-
-        output.push(BASE64_CHARS[((triple >> 18) & 0x3F) as usize] as char);
-        output.push(BASE64_CHARS[((triple >> 12) & 0x3F) as usize] as char);
-
-        if chunk.len() > 1 {
-            output.push(BASE64_CHARS[((triple >> 6) & 0x3F) as usize] as char);
-        } else {
-            output.push('=');
+/// Closes `ws` with `frame`, unless a close was already sent, and waits up to
+/// [`CLOSE_TIMEOUT`] for the server's close, which is returned. Failures are only
+/// logged, since the connection is being dropped either way.
+pub async fn close_gracefully(ws: &mut WebSocket, frame: CloseFrame) -> Option<CloseFrame> {
+    match with_timeout(CLOSE_TIMEOUT, ws.close(Some(frame))).await {
+        Ok(Ok(close)) => close,
+        Ok(Err(e)) => {
+            log::warn!("WebSocket closing handshake failed: {e}");
+            None
         }
-
-        if chunk.len() > 2 {
-            output.push(BASE64_CHARS[(triple & 0x3F) as usize] as char);
-        } else {
-            output.push('=');
+        Err(_) => {
+            log::warn!("Server did not answer WebSocket close");
+            None
         }
     }
+}
 
-    output
+/// Fills `buf` from the ESP hardware RNG.
+pub(super) fn fill_random(buf: &mut [u8]) {
+    unsafe {
+        esp_idf_svc::sys::esp_fill_random(buf.as_mut_ptr() as *mut core::ffi::c_void, buf.len());
+    }
 }
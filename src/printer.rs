@@ -1,4 +1,7 @@
-use crate::net::http;
+use std::sync::{Arc, Mutex};
+
+use crate::net::{http, DeviceConfig};
+use crate::settings;
 
 #[derive(Debug, Clone, Copy, serde::Serialize)]
 pub enum UnderlineMode {
@@ -67,15 +70,28 @@ impl PrinterEvent {
     }
 }
 
-pub async fn post_event(event: PrinterEvent) -> anyhow::Result<()> {
+/// Prints the receipt for `event`, signed with the device key if there is one. Does
+/// nothing while the printer is turned off in the settings.
+pub async fn post_event(
+    event: PrinterEvent,
+    config: &Arc<Mutex<DeviceConfig>>,
+) -> anyhow::Result<()> {
+    if !settings::get_bool(settings::PRINTER_ENABLED) {
+        return Ok(());
+    }
     let data = serde_json::to_string(&event.message())?;
+    let key = config.lock().unwrap().get_device_key();
 
-    http::http_post(
-        "https://api.purduehackers.com/printer/print",
-        &[("Content-Type", "application/json")],
-        data.as_bytes(),
-    )
-    .await?;
+    // A duplicate receipt is worse than a missed one, so never retry
+    let url = settings::get_string(settings::PRINTER_URL);
+    let mut request = http::Request::post(&url)
+        .header("Content-Type", "application/json")
+        .body(data.as_bytes())
+        .retry(http::RetryPolicy::none());
+    if let Some(key) = &key {
+        request = request.signed(key);
+    }
+    request.send().await?;
 
     Ok(())
 }
@@ -0,0 +1,331 @@
+//! Settings the server can change at runtime with `get_config`/`set_config`. Each one
+//! has a type, a default and limits in [`REGISTRY`]. Only values that differ from the
+//! default are stored, so changing a default in a new firmware reaches every sign that
+//! wasn't configured otherwise.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use chrono::Weekday;
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::net::DeviceConfig;
+
+pub const TIMEZONE: &str = "timezone";
+pub const AUTO_UPDATE: &str = "auto_update";
+pub const UPDATE_DAY: &str = "update_day";
+pub const UPDATE_HOUR: &str = "update_hour";
+pub const TELEMETRY_INTERVAL: &str = "telemetry_interval_secs";
+pub const PRINTER_ENABLED: &str = "printer_enabled";
+pub const PRINTER_URL: &str = "printer_url";
+
+/// Shortest telemetry interval, other than 0 to only push after notable events.
+pub const MIN_TELEMETRY_INTERVAL_SECS: i64 = 10;
+pub const MAX_TELEMETRY_INTERVAL_SECS: i64 = 24 * 60 * 60;
+
+const WEEKDAYS: &[&str] = &["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+pub static REGISTRY: [Setting; 7] = [
+    Setting {
+        key: TIMEZONE,
+        kind: Kind::Str { max_len: 64 },
+        default: Value::Str(Cow::Borrowed("America/New_York")),
+        check: Some(check_timezone),
+    },
+    Setting {
+        key: AUTO_UPDATE,
+        kind: Kind::Bool,
+        default: Value::Bool(true),
+        check: None,
+    },
+    Setting {
+        key: UPDATE_DAY,
+        kind: Kind::Choice { choices: WEEKDAYS },
+        default: Value::Str(Cow::Borrowed("sat")),
+        check: None,
+    },
+    Setting {
+        key: UPDATE_HOUR,
+        kind: Kind::Int { min: 0, max: 23 },
+        default: Value::Int(3),
+        check: None,
+    },
+    Setting {
+        key: TELEMETRY_INTERVAL,
+        kind: Kind::Int {
+            min: 0,
+            max: MAX_TELEMETRY_INTERVAL_SECS,
+        },
+        default: Value::Int(60),
+        check: Some(check_telemetry_interval),
+    },
+    Setting {
+        key: PRINTER_ENABLED,
+        kind: Kind::Bool,
+        default: Value::Bool(true),
+        check: None,
+    },
+    Setting {
+        key: PRINTER_URL,
+        kind: Kind::Str { max_len: 256 },
+        default: Value::Str(Cow::Borrowed("https://api.purduehackers.com/printer/print")),
+        check: Some(check_https_url),
+    },
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Bool(bool),
+    Int(i64),
+    Str(Cow<'static, str>),
+}
+
+impl Value {
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// The type of a setting and the values it accepts.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Kind {
+    Bool,
+    Int { min: i64, max: i64 },
+    Str { max_len: usize },
+    Choice { choices: &'static [&'static str] },
+}
+
+#[derive(Debug)]
+pub struct Setting {
+    pub key: &'static str,
+    pub kind: Kind,
+    pub default: Value,
+    /// Anything `kind` can't express.
+    check: Option<fn(&Value) -> Result<(), String>>,
+}
+
+impl Setting {
+    fn validate(&self, value: &Value) -> Result<(), String> {
+        let key = self.key;
+        match (self.kind, value) {
+            (Kind::Bool, Value::Bool(_)) => {}
+            (Kind::Int { min, max }, Value::Int(n)) => {
+                if !(min..=max).contains(n) {
+                    return Err(format!("{key} must be {min} to {max}"));
+                }
+            }
+            (Kind::Str { max_len }, Value::Str(s)) => {
+                if s.len() > max_len {
+                    return Err(format!("{key} must be at most {max_len} bytes"));
+                }
+            }
+            (Kind::Choice { choices }, Value::Str(s)) => {
+                if !choices.contains(&s.as_ref()) {
+                    return Err(format!("{key} must be one of {}", choices.join(", ")));
+                }
+            }
+            (Kind::Bool, _) => return Err(format!("{key} must be a boolean")),
+            (Kind::Int { .. }, _) => return Err(format!("{key} must be an integer")),
+            (Kind::Str { .. } | Kind::Choice { .. }, _) => {
+                return Err(format!("{key} must be a string"))
+            }
+        }
+        self.check.map_or(Ok(()), |check| check(value))
+    }
+}
+
+/// A setting as reported by `get_config`.
+#[derive(Debug, Serialize)]
+pub struct SettingInfo {
+    pub key: &'static str,
+    pub value: Value,
+    pub default: Value,
+    #[serde(flatten)]
+    pub kind: Kind,
+}
+
+/// Values that differ from the default.
+static VALUES: Mutex<BTreeMap<&'static str, Value>> = Mutex::new(BTreeMap::new());
+/// Called with the new value whenever a setting changes.
+static SUBSCRIBERS: Mutex<Vec<(&'static str, fn(&Value))>> = Mutex::new(Vec::new());
+
+fn setting(key: &str) -> Option<&'static Setting> {
+    REGISTRY.iter().find(|setting| setting.key == key)
+}
+
+/// Checks `value` against the setting's type, limits and any extra rules.
+pub fn validate(key: &str, value: &Value) -> Result<(), String> {
+    match setting(key) {
+        Some(setting) => setting.validate(value),
+        None => Err(format!("Unknown setting {key:?}")),
+    }
+}
+
+/// Reads the stored values. Ones that are unknown or no longer valid are ignored.
+pub fn load(config: &DeviceConfig) {
+    let mut values = VALUES.lock().unwrap();
+    for (key, value) in config.get_settings() {
+        match setting(&key) {
+            Some(setting) => match setting.validate(&value) {
+                Ok(()) => {
+                    values.insert(setting.key, value);
+                }
+                Err(e) => log::warn!("Ignoring stored setting: {e}"),
+            },
+            None => log::warn!("Ignoring unknown stored setting {key:?}"),
+        }
+    }
+    drop(values);
+
+    for setting in &REGISTRY {
+        notify(setting.key, &get(setting.key));
+    }
+}
+
+/// Checks, stores and applies `changes`. Nothing changes unless all of them are valid.
+pub fn set(
+    config: &Arc<Mutex<DeviceConfig>>,
+    changes: HashMap<String, Value>,
+) -> anyhow::Result<()> {
+    let mut changed = Vec::new();
+    for (key, value) in changes {
+        let Some(setting) = setting(&key) else {
+            anyhow::bail!("Unknown setting {key:?}");
+        };
+        setting.validate(&value).map_err(anyhow::Error::msg)?;
+        changed.push((setting, value));
+    }
+
+    let mut updated = VALUES.lock().unwrap().clone();
+    for (setting, value) in &changed {
+        if *value == setting.default {
+            updated.remove(setting.key);
+        } else {
+            updated.insert(setting.key, value.clone());
+        }
+    }
+    config.lock().unwrap().set_settings(&updated)?;
+    *VALUES.lock().unwrap() = updated;
+
+    for (setting, value) in &changed {
+        log::info!("Setting {} changed to {value:?}", setting.key);
+        notify(setting.key, value);
+    }
+    Ok(())
+}
+
+/// Current value of a registered setting.
+pub fn get(key: &str) -> Value {
+    if let Some(value) = VALUES.lock().unwrap().get(key) {
+        return value.clone();
+    }
+    setting(key)
+        .map(|setting| setting.default.clone())
+        .unwrap_or_else(|| panic!("Unregistered setting {key:?}"))
+}
+
+pub fn all() -> Vec<SettingInfo> {
+    REGISTRY
+        .iter()
+        .map(|setting| SettingInfo {
+            key: setting.key,
+            value: get(setting.key),
+            default: setting.default.clone(),
+            kind: setting.kind,
+        })
+        .collect()
+}
+
+/// Calls `callback` with the new value each time `key` changes, and once when the
+/// stored settings are loaded at boot. Runs on whichever task made the change, so it
+/// should only hand the value off.
+pub fn subscribe(key: &'static str, callback: fn(&Value)) {
+    SUBSCRIBERS.lock().unwrap().push((key, callback));
+}
+
+fn notify(key: &str, value: &Value) {
+    let callbacks: Vec<_> = SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(subscribed, _)| *subscribed == key)
+        .map(|(_, callback)| *callback)
+        .collect();
+    for callback in callbacks {
+        callback(value);
+    }
+}
+
+pub fn get_bool(key: &str) -> bool {
+    get(key).as_bool().unwrap_or_default()
+}
+
+pub fn get_int(key: &str) -> i64 {
+    get(key).as_int().unwrap_or_default()
+}
+
+pub fn get_string(key: &str) -> String {
+    get(key).as_str().unwrap_or_default().to_string()
+}
+
+pub fn timezone() -> Tz {
+    get_string(TIMEZONE)
+        .parse()
+        .unwrap_or(chrono_tz::US::Eastern)
+}
+
+/// Day and hour of the weekly update check in the [`TIMEZONE`] the clock shows, or
+/// `None` if automatic updates are off.
+pub fn update_schedule() -> Option<(Weekday, u32)> {
+    if !get_bool(AUTO_UPDATE) {
+        return None;
+    }
+    let day = get_string(UPDATE_DAY).parse().unwrap_or(Weekday::Sat);
+    Some((day, get_int(UPDATE_HOUR) as u32))
+}
+
+fn check_timezone(value: &Value) -> Result<(), String> {
+    let name = value.as_str().unwrap_or_default();
+    match name.parse::<Tz>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("Unknown timezone {name:?}")),
+    }
+}
+
+fn check_telemetry_interval(value: &Value) -> Result<(), String> {
+    match value.as_int() {
+        Some(secs) if secs != 0 && secs < MIN_TELEMETRY_INTERVAL_SECS => Err(format!(
+            "{TELEMETRY_INTERVAL} must be 0 or at least {MIN_TELEMETRY_INTERVAL_SECS}"
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_https_url(value: &Value) -> Result<(), String> {
+    let url = value.as_str().unwrap_or_default();
+    match url::Url::parse(url) {
+        Ok(url) if url.scheme() == "https" && url.host().is_some() => Ok(()),
+        _ => Err(format!("{url:?} is not an HTTPS URL")),
+    }
+}
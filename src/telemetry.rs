@@ -0,0 +1,115 @@
+//! Compact status pushed to the server on an interval, and right away after notable
+//! events. Unlike `get_status`, it's meant to be cheap enough to send often.
+
+use std::sync::Mutex;
+
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use esp_idf_svc::sys;
+use palette::Srgb;
+use serde::Serialize;
+
+use crate::net::ws;
+use crate::{health, settings};
+
+static PUSH_NOW: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+static FRAMES: Mutex<FrameWindow> = Mutex::new(FrameWindow {
+    count: 0,
+    total_us: 0,
+    max_us: 0,
+    colors: [Srgb::new(0, 0, 0); 5],
+});
+
+/// Frames rendered since the last push.
+struct FrameWindow {
+    count: u32,
+    total_us: u64,
+    max_us: u64,
+    colors: [Srgb<u8>; 5],
+}
+
+/// Follows changes to the interval setting.
+pub fn init() {
+    // Start the new interval now rather than after the old one runs out
+    settings::subscribe(settings::TELEMETRY_INTERVAL, |_| push_now());
+}
+
+/// Pushes as soon as the server connection is free to, even if pushes are off.
+pub fn push_now() {
+    PUSH_NOW.signal(());
+}
+
+/// Waits until the next push is due, counting from `last`. Cancel-safe.
+pub async fn due(last: Instant) {
+    match settings::get_int(settings::TELEMETRY_INTERVAL) {
+        0 => PUSH_NOW.wait().await,
+        secs => {
+            let next = last + Duration::from_secs(secs as u64);
+            select(Timer::at(next), PUSH_NOW.wait()).await;
+        }
+    }
+}
+
+/// Called by the render loop after each frame with how long it took and what the
+/// LEDs now show.
+pub fn record_frame(took: Duration, colors: &[Srgb<u8>; 5]) {
+    let mut frames = FRAMES.lock().unwrap();
+    let took = took.as_micros();
+    frames.count = frames.count.saturating_add(1);
+    frames.total_us += took;
+    frames.max_us = frames.max_us.max(took);
+    frames.colors = *colors;
+}
+
+#[derive(Debug, Serialize)]
+pub struct Telemetry {
+    pub uptime_secs: u64,
+    /// `None` while not associated with an access point.
+    pub rssi: Option<i8>,
+    pub free_heap: u32,
+    pub min_free_heap: u32,
+    pub reconnects: u32,
+    pub consecutive_failures: u32,
+    pub frames: FrameStats,
+    /// What each block shows, as `#rrggbb`.
+    pub colors: [String; 5],
+}
+
+/// Render loop timing since the previous push.
+#[derive(Debug, Serialize)]
+pub struct FrameStats {
+    pub count: u32,
+    pub avg_us: u64,
+    pub max_us: u64,
+}
+
+/// Current telemetry. Starts a new window for the frame stats.
+pub fn take() -> Telemetry {
+    let (frames, colors) = {
+        let mut window = FRAMES.lock().unwrap();
+        let stats = FrameStats {
+            count: window.count,
+            avg_us: window.total_us / u64::from(window.count.max(1)),
+            max_us: window.max_us,
+        };
+        window.count = 0;
+        window.total_us = 0;
+        window.max_us = 0;
+        (stats, window.colors)
+    };
+    let connection = ws::status();
+
+    Telemetry {
+        uptime_secs: unsafe { sys::esp_timer_get_time() } as u64 / 1_000_000,
+        rssi: health::wifi_status().map(|wifi| wifi.rssi),
+        free_heap: unsafe { sys::esp_get_free_heap_size() },
+        min_free_heap: unsafe { sys::esp_get_minimum_free_heap_size() },
+        reconnects: connection.reconnects,
+        consecutive_failures: connection.consecutive_failures,
+        frames,
+        colors: colors.map(|c| format!("#{:02x}{:02x}{:02x}", c.red, c.green, c.blue)),
+    }
+}